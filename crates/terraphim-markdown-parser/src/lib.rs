@@ -1,14 +1,384 @@
-pub fn add(left: usize, right: usize) -> usize {
-    left + right
+//! Parses Obsidian style Markdown notes into articles: title and tags from
+//! the YAML front matter, the body as plain text and `[[wikilinks]]` as
+//! explicit links between notes.
+use std::path::{Path, PathBuf};
+
+use lazy_static::lazy_static;
+use pulldown_cmark::{Event, HeadingLevel, Options, Parser, Tag};
+use regex::{Captures, Regex};
+use serde::{Serialize, Serializer};
+use serde_yaml::Value;
+use thiserror::Error;
+
+lazy_static! {
+    static ref WIKILINK: Regex = Regex::new(r"(!?)\[\[([^\[\]]+?)\]\]").unwrap();
+}
+
+#[derive(Debug, Error)]
+pub enum ParseError {
+    #[error("failed to read {path}: {source}")]
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("invalid front matter in {path}: {source}")]
+    FrontMatter {
+        path: String,
+        source: serde_yaml::Error,
+    },
+}
+
+/// A `[[target#heading|alias]]` link to another note
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct WikiLink {
+    /// Name of the linked note, without heading or alias
+    pub target: String,
+    pub heading: Option<String>,
+    pub alias: Option<String>,
+    /// `![[...]]` embeds the target instead of linking to it
+    pub embed: bool,
+}
+
+impl WikiLink {
+    fn parse(inner: &str, embed: bool) -> Option<Self> {
+        let (link, alias) = match inner.split_once('|') {
+            Some((link, alias)) => (link, Some(alias.trim().to_string())),
+            None => (inner, None),
+        };
+        let (target, heading) = match link.split_once('#') {
+            Some((target, heading)) => (target, Some(heading.trim().to_string())),
+            None => (link, None),
+        };
+        let target = target.trim();
+        if target.is_empty() {
+            return None;
+        }
+        Some(WikiLink {
+            target: target.to_string(),
+            heading,
+            alias,
+            embed,
+        })
+    }
+
+    /// Text a reader sees in place of the link
+    pub fn display(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.target)
+    }
+}
+
+/// A parsed note, shaped like the server's `Article`
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Note {
+    /// Stable id: `id` from the front matter or a slug of the note's path
+    pub id: String,
+    pub title: String,
+    pub url: String,
+    /// Plain text, one line per paragraph, heading or list item
+    pub body: String,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+    /// `published` or `date` from the front matter
+    pub published: Option<String>,
+    /// Serialized as the targets of the links, see `link_targets`
+    #[serde(serialize_with = "serialize_link_targets")]
+    pub links: Vec<WikiLink>,
+}
+
+impl Note {
+    /// Distinct targets of the note's links, embeds left out
+    pub fn link_targets(&self) -> Vec<String> {
+        link_targets(&self.links)
+    }
+}
+
+fn link_targets(links: &[WikiLink]) -> Vec<String> {
+    let mut targets: Vec<String> = Vec::new();
+    for link in links.iter().filter(|l| !l.embed) {
+        if !targets.contains(&link.target) {
+            targets.push(link.target.clone());
+        }
+    }
+    targets
+}
+
+fn serialize_link_targets<S: Serializer>(
+    links: &[WikiLink],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    link_targets(links).serialize(serializer)
+}
+
+/// Splits `---` delimited YAML front matter off the start of `input`.
+fn split_front_matter(input: &str) -> (Option<&str>, &str) {
+    let rest = match input.trim_start_matches('\u{feff}').strip_prefix("---") {
+        Some(rest) if rest.starts_with('\n') || rest.starts_with("\r\n") => rest,
+        _ => return (None, input),
+    };
+    let mut offset = 0;
+    for line in rest.split_inclusive('\n') {
+        if offset > 0 && line.trim_end() == "---" {
+            return (Some(&rest[..offset]), &rest[offset + line.len()..]);
+        }
+        offset += line.len();
+    }
+    (None, input)
+}
+
+fn yaml_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.trim().to_string()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+    .filter(|s| !s.is_empty())
+}
+
+/// Tags as a YAML list or a comma/space separated string, without `#`
+fn yaml_tags(value: &Value) -> Vec<String> {
+    let tags: Vec<String> = match value {
+        Value::Sequence(items) => items.iter().filter_map(yaml_string).collect(),
+        value => yaml_string(value)
+            .map(|s| {
+                s.split(|c: char| c == ',' || c.is_whitespace())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default(),
+    };
+    tags.into_iter()
+        .map(|t| t.trim().trim_start_matches('#').to_string())
+        .filter(|t| !t.is_empty())
+        .collect()
+}
+
+/// Lowercase id made of the alphanumeric runs of `path`
+pub fn slug(path: &str) -> String {
+    path.split(|c: char| !c.is_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Renders Markdown as plain text and returns the first level 1 heading.
+fn plain_text(markdown: &str) -> (String, Option<String>) {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+    options.insert(Options::ENABLE_TASKLISTS);
+    options.insert(Options::ENABLE_TABLES);
+    let mut body = String::new();
+    let mut heading: Option<String> = None;
+    let mut in_title = false;
+    for event in Parser::new_ext(markdown, options) {
+        match event {
+            Event::Start(Tag::Heading(HeadingLevel::H1, ..)) if heading.is_none() => {
+                in_title = true;
+                heading = Some(String::new());
+            }
+            Event::End(Tag::Heading(..)) => {
+                in_title = false;
+                body.push('\n');
+            }
+            Event::Text(text) | Event::Code(text) => {
+                if in_title {
+                    if let Some(heading) = heading.as_mut() {
+                        heading.push_str(&text);
+                    }
+                }
+                body.push_str(&text);
+            }
+            Event::SoftBreak => body.push(' '),
+            Event::HardBreak => body.push('\n'),
+            Event::End(Tag::Paragraph)
+            | Event::End(Tag::Item)
+            | Event::End(Tag::CodeBlock(_))
+            | Event::End(Tag::TableRow) => body.push('\n'),
+            _ => {}
+        }
+    }
+    let body = body
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+    (
+        body,
+        heading
+            .map(|h| h.trim().to_string())
+            .filter(|h| !h.is_empty()),
+    )
+}
+
+/// Parses a note. `path` is the note's path relative to its vault, used for
+/// the fallback id and title and in errors.
+pub fn parse_note(input: &str, path: &Path) -> Result<Note, ParseError> {
+    let (front_matter, markdown) = split_front_matter(input);
+    let front_matter: Value = match front_matter {
+        Some(yaml) => serde_yaml::from_str(yaml).map_err(|source| ParseError::FrontMatter {
+            path: path.display().to_string(),
+            source,
+        })?,
+        None => Value::Null,
+    };
+    let field = |name: &str| front_matter.get(name).and_then(yaml_string);
+
+    let mut links = Vec::new();
+    let markdown = WIKILINK.replace_all(markdown, |caps: &Captures| {
+        match WikiLink::parse(&caps[2], !caps[1].is_empty()) {
+            Some(link) => {
+                let display = if link.embed {
+                    String::new()
+                } else {
+                    link.display().to_string()
+                };
+                links.push(link);
+                display
+            }
+            None => caps[0].to_string(),
+        }
+    });
+    let (body, heading) = plain_text(&markdown);
+
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let relative = path.with_extension("");
+    Ok(Note {
+        id: field("id").unwrap_or_else(|| slug(&relative.to_string_lossy())),
+        title: field("title").or(heading).unwrap_or(stem),
+        url: field("url").unwrap_or_else(|| path.display().to_string()),
+        body,
+        description: field("description").or_else(|| field("summary")),
+        tags: front_matter.get("tags").map(yaml_tags).unwrap_or_default(),
+        published: field("published").or_else(|| field("date")),
+        links,
+    })
+}
+
+/// Expands a leading `~` to the home directory, as used by role haystacks.
+pub fn expand_home(path: &str) -> PathBuf {
+    match path.strip_prefix('~') {
+        Some(rest) => match std::env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(rest.trim_start_matches('/')),
+            None => PathBuf::from(path),
+        },
+        None => PathBuf::from(path),
+    }
+}
+
+/// Markdown files below `dir`, skipping hidden files and directories such
+/// as `.obsidian` and `.trash`. Sorted for a stable order.
+pub fn markdown_files(dir: &Path) -> Result<Vec<PathBuf>, ParseError> {
+    files_with_extensions(dir, &["md"])
+}
+
+/// Files below `dir` with one of `extensions`, skipping hidden files and
+/// directories. Sorted for a stable order.
+pub fn files_with_extensions(dir: &Path, extensions: &[&str]) -> Result<Vec<PathBuf>, ParseError> {
+    let mut files = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = std::fs::read_dir(&dir).map_err(|source| ParseError::Io {
+            path: dir.display().to_string(),
+            source,
+        })?;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path
+                .extension()
+                .is_some_and(|e| extensions.iter().any(|x| e == *x))
+            {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Notes of a directory with their paths, or the error reading the directory
+pub type ParsedDir = Result<Vec<(PathBuf, Result<Note, ParseError>)>, ParseError>;
+
+/// Parses every note of a vault directory. The note path relative to `dir`
+/// is kept with each result so failures can be reported per file.
+pub fn parse_dir(dir: &Path) -> ParsedDir {
+    parse_dir_in(dir, dir)
+}
+
+/// Parses the notes of `dir`, a directory inside the vault `root`. Note
+/// paths, and so the ids derived from them, are relative to `root`, the
+/// same whichever directory of the vault is parsed.
+pub fn parse_dir_in(dir: &Path, root: &Path) -> ParsedDir {
+    let notes = markdown_files(dir)?
+        .into_iter()
+        .map(|path| {
+            let relative = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+            let note = std::fs::read_to_string(&path)
+                .map_err(|source| ParseError::Io {
+                    path: path.display().to_string(),
+                    source,
+                })
+                .and_then(|input| parse_note(&input, &relative));
+            (relative, note)
+        })
+        .collect();
+    Ok(notes)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    const NOTE: &str = r##"---
+title: My Document
+tags: [example, "#rust"]
+date: 2021-06-30
+---
+
+# Heading
+
+This is a paragraph with a [[wikilink]] and an [[Other Note#Usage|alias]].
+
+Another paragraph with a [regular link](https://www.example.com).
+![[diagram.png]]
+"##;
+
+    #[test]
+    fn test_parse_note() {
+        let note = parse_note(NOTE, Path::new("World/My Document.md")).unwrap();
+        assert_eq!(note.id, "world-my-document");
+        assert_eq!(note.title, "My Document");
+        assert_eq!(note.tags, vec!["example", "rust"]);
+        assert_eq!(note.published.as_deref(), Some("2021-06-30"));
+        assert_eq!(
+            note.body,
+            "Heading\nThis is a paragraph with a wikilink and an alias.\nAnother paragraph with a regular link."
+        );
+        let targets: Vec<&str> = note.links.iter().map(|l| l.target.as_str()).collect();
+        assert_eq!(targets, vec!["wikilink", "Other Note", "diagram.png"]);
+        assert_eq!(note.links[1].heading.as_deref(), Some("Usage"));
+        assert!(note.links[2].embed);
+        assert_eq!(note.link_targets(), vec!["wikilink", "Other Note"]);
+    }
+
     #[test]
-    fn it_works() {
-        let result = add(2, 2);
-        assert_eq!(result, 4);
+    fn test_parse_note_without_front_matter() {
+        let note = parse_note("# Title\n\n---\n\ntext", Path::new("notes/a.md")).unwrap();
+        assert_eq!(note.title, "Title");
+        assert_eq!(note.body, "Title\ntext");
+        assert!(note.tags.is_empty());
+        assert_eq!(note.published, None);
+        let note = parse_note("plain text", Path::new("b.md")).unwrap();
+        assert_eq!(note.title, "b");
+        assert!(parse_note("---\ntags: [a\n---\n", Path::new("c.md")).is_err());
     }
 }
@@ -1,43 +1,30 @@
-extern crate pulldown_cmark;
-use pulldown_cmark::{html, Options, Parser, Tag};
-
-fn main() {
-    let markdown_input = r#"
----
-title: My Document
-tags: [example, rust]
----
-
-# Heading
-
-This is a paragraph with a [[wikilink]].
-
-Another paragraph with a [regular link](https://www.example.com).
-"#;
-
-    let mut options = Options::empty();
-    options.insert(Options::ENABLE_STRIKETHROUGH);
-    options.insert(Options::ENABLE_TASKLISTS);
-
-    let parser = Parser::new_ext(markdown_input, options)
-        .map(|event| match event {
-            pulldown_cmark::Event::Text(text) => {
-                if text.starts_with("[[") && text.ends_with("]]") {
-                    let link_text = text.trim_matches(|c| c == '[' || c == ']');
-                    pulldown_cmark::Event::Start(Tag::Link(
-                        pulldown_cmark::LinkType::Shortcut,
-                        link_text.to_string().into(),
-                        link_text.to_string().into(),
-                    ))
-                } else {
-                    pulldown_cmark::Event::Text(text)
-                }
+use std::io::Write;
+
+use clap::Parser;
+use terraphim_markdown_parser::{expand_home, parse_dir};
+
+/// Parses the Markdown notes of a vault into one JSON article per line, ready
+/// for `POST /articles/bulk` with `Content-Type: application/x-ndjson`
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// Vault directories, `~` is expanded
+    #[arg(required = true)]
+    dirs: Vec<String>,
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = Args::parse();
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    for dir in args.dirs {
+        let dir = expand_home(&dir);
+        for (path, note) in parse_dir(&dir)? {
+            match note {
+                Ok(note) => writeln!(out, "{}", serde_json::to_string(&note)?)?,
+                Err(e) => eprintln!("Skipping {}: {}", path.display(), e),
             }
-            _ => event,
-        });
-
-    let mut html_output = String::new();
-    html::push_html(&mut html_output, parser);
-
-    println!("{}", html_output);
-}
\ No newline at end of file
+        }
+    }
+    Ok(())
+}
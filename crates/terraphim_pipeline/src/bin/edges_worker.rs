@@ -0,0 +1,133 @@
+use clap::Parser;
+use redis::{Commands, ConnectionLike};
+use terraphim_pipeline::shard::stream_name;
+use terraphim_pipeline::worker::{EdgeWorker, WorkerConfig};
+
+const STREAM_PATTERN: &str = "edges_matched_*";
+
+/// Writes edges from the `edges_matched_*` streams into the role graphs and `edges_scored:*`
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    #[arg(
+        long,
+        env = "TERRAPHIM_REDIS_URL",
+        default_value = "redis://127.0.0.1:6379"
+    )]
+    redis_url: String,
+    /// `standalone` to use `redis_url`, `cluster` to use `redis_cluster_url`
+    #[arg(long, env = "TERRAPHIM_REDIS_MODE", default_value = "standalone")]
+    redis_mode: String,
+    /// Comma separated urls of Redis Cluster nodes, used in `cluster` mode
+    #[arg(long, env = "TERRAPHIM_REDIS_CLUSTER_URL", default_value = "")]
+    redis_cluster_url: String,
+    /// Consumer group shared by all workers
+    #[arg(long, default_value = "graph_writer")]
+    group: String,
+    /// Name of this worker, keep it stable so a restart resumes its pending entries
+    #[arg(long, env = "TERRAPHIM_WORKER_NAME", default_value = "worker-1")]
+    consumer: String,
+    #[arg(long, default_value_t = 100)]
+    batch_size: usize,
+    #[arg(long, default_value_t = 5000)]
+    block_ms: usize,
+    /// Reclaim and retry entries pending for longer than this
+    #[arg(long, default_value_t = 60000)]
+    min_idle_ms: usize,
+    /// Move entries delivered this many times to `edges_dead:{stream}`
+    #[arg(long, default_value_t = 5)]
+    max_deliveries: usize,
+    /// How often to look for new `edges_matched_*` streams when none are named
+    #[arg(long, default_value_t = 60000)]
+    rescan_ms: usize,
+    /// Consume every shard of these roles, including shards nothing was written to yet
+    #[arg(long = "role")]
+    roles: Vec<String>,
+    /// Number of edge stream shards per role, as in the server settings
+    #[arg(long, env = "TERRAPHIM_EDGE_SHARD_COUNT", default_value_t = 1)]
+    shard_count: u32,
+    /// Streams to consume, defaults to every existing `edges_matched_*` stream
+    streams: Vec<String>,
+}
+
+impl Args {
+    /// Streams named on the command line or of the given roles
+    fn streams(&self) -> Vec<String> {
+        let mut streams = self.streams.clone();
+        for role in self.roles.iter() {
+            streams.extend(
+                (0..self.shard_count).map(|shard| stream_name(role, shard, self.shard_count)),
+            );
+        }
+        streams
+    }
+
+    fn config(&self) -> WorkerConfig {
+        WorkerConfig {
+            group: self.group.clone(),
+            consumer: self.consumer.clone(),
+            batch_size: self.batch_size,
+            block_ms: self.block_ms,
+            min_idle_ms: self.min_idle_ms,
+            max_deliveries: self.max_deliveries,
+            rescan_ms: self.rescan_ms,
+        }
+    }
+}
+
+fn run<C: ConnectionLike>(
+    con: C,
+    config: WorkerConfig,
+    mut streams: Vec<String>,
+    pattern: Option<&str>,
+) -> redis::RedisResult<()> {
+    streams.sort();
+    streams.dedup();
+    println!("Consuming {:?}", streams);
+    let mut worker = EdgeWorker::new(con, config, streams)?;
+    if let Some(pattern) = pattern {
+        worker = worker.discover(pattern);
+    }
+    worker.run()
+}
+
+fn main() -> redis::RedisResult<()> {
+    let args = Args::parse();
+    match args.redis_mode.as_str() {
+        "standalone" => {}
+        "cluster" => {
+            let nodes: Vec<&str> = args
+                .redis_cluster_url
+                .split(',')
+                .map(str::trim)
+                .filter(|url| !url.is_empty())
+                .collect();
+            if nodes.is_empty() {
+                eprintln!("Name the cluster nodes with --redis-cluster-url");
+                std::process::exit(2);
+            }
+            // SCAN only sees the keys of one node, the streams have to be named
+            let streams = args.streams();
+            if streams.is_empty() {
+                eprintln!("Name the streams or roles to consume on a cluster");
+                std::process::exit(2);
+            }
+            let con = redis::cluster::ClusterClient::new(nodes)?.get_connection()?;
+            return run(con, args.config(), streams, None);
+        }
+        mode => {
+            eprintln!("Unknown redis mode {mode}, use standalone or cluster");
+            std::process::exit(2);
+        }
+    }
+    let client = redis::Client::open(args.redis_url.as_str())?;
+    let mut con = client.get_connection()?;
+    let mut streams = args.streams();
+    // streams of roles added later are picked up while running
+    let mut pattern = None;
+    if streams.is_empty() {
+        streams = con.scan_match(STREAM_PATTERN)?.collect();
+        pattern = Some(STREAM_PATTERN);
+    }
+    run(con, args.config(), streams, pattern)
+}
@@ -0,0 +1,353 @@
+use std::collections::BTreeMap;
+
+/// Relationship type of edges extracted from sentences
+pub const CO_OCCURS: &str = "CO_OCCURS";
+/// Relationship type of `[[wikilinks]]` from a note to a concept or article
+pub const LINKS_TO: &str = "LINKS_TO";
+
+/// Whether `relation` can be used as a relationship type in a query,
+/// types can't be passed as query parameters
+pub fn valid_relation(relation: &str) -> bool {
+    !relation.is_empty() && relation.chars().all(|c| c.is_ascii_uppercase() || c == '_')
+}
+
+/// A co-occurrence of two concepts, as written to the `edges_matched_*`
+/// streams and merged into the role graph. Concepts are named by their
+/// normalised term, the synonyms are the terms found in the text. Links
+/// have no synonyms, an article title or link text isn't a term of a concept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EdgeRecord {
+    pub source: String,
+    pub destination: String,
+    pub source_name: String,
+    pub destination_name: String,
+    pub source_synonym: Option<String>,
+    pub destination_synonym: Option<String>,
+    pub rank: f64,
+    pub year: i64,
+    /// Relationship type, `CO_OCCURS` or `LINKS_TO`
+    pub relation: String,
+    /// Number of the sentence the concepts co-occur in, see `sentence_key`
+    pub sentence: Option<usize>,
+}
+
+/// Groups edges by relationship type, each type needs its own query
+pub fn by_relation(edges: &[EdgeRecord]) -> BTreeMap<&str, Vec<EdgeRecord>> {
+    let mut grouped: BTreeMap<&str, Vec<EdgeRecord>> = BTreeMap::new();
+    for edge in edges {
+        grouped
+            .entry(edge.relation.as_str())
+            .or_default()
+            .push(edge.clone());
+    }
+    grouped
+}
+
+/// What one article added to one edge of a role graph. Recorded in the
+/// `article_edges:{article}` hash so it can be retracted when the article
+/// is updated or deleted.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Contribution {
+    pub graph: String,
+    pub source: String,
+    pub destination: String,
+    pub year: i64,
+    pub rank: f64,
+    pub relation: String,
+}
+
+impl Contribution {
+    pub fn new(graph: &str, edge: &EdgeRecord) -> Self {
+        Contribution {
+            graph: graph.to_string(),
+            source: edge.source.clone(),
+            destination: edge.destination.clone(),
+            year: edge.year,
+            rank: edge.rank,
+            relation: edge.relation.clone(),
+        }
+    }
+
+    /// Hash field identifying the edge, the JSON array
+    /// `[graph, source, destination, year, relation]`. Article ids and
+    /// graph names may contain any delimiter.
+    pub fn field(&self) -> String {
+        serde_json::to_string(&(
+            &self.graph,
+            &self.source,
+            &self.destination,
+            self.year,
+            &self.relation,
+        ))
+        .expect("a tuple of strings and an integer serializes")
+    }
+
+    /// Inverse of `field`. Also reads the `{graph}:{source}:{destination}:{year}[:{relation}]`
+    /// fields recorded before, which are only right when no part but the
+    /// graph name contains `:`.
+    pub fn parse(field: &str, rank: f64) -> Option<Self> {
+        if field.starts_with('[') {
+            let (graph, source, destination, year, relation) = serde_json::from_str(field).ok()?;
+            return Some(Contribution {
+                graph,
+                source,
+                destination,
+                year,
+                rank,
+                relation,
+            });
+        }
+        let (field, relation) = match field.rsplit_once(':') {
+            Some((rest, last)) if valid_relation(last) => (rest, last),
+            _ => (field, CO_OCCURS),
+        };
+        let mut parts = field.rsplitn(4, ':');
+        let year = parts.next()?.parse().ok()?;
+        let destination = parts.next()?.to_string();
+        let source = parts.next()?.to_string();
+        let graph = parts.next()?.to_string();
+        Some(Contribution {
+            graph,
+            source,
+            destination,
+            year,
+            rank,
+            relation: relation.to_string(),
+        })
+    }
+}
+
+pub fn article_edges_key(article: &str) -> String {
+    format!("article_edges:{article}")
+}
+
+/// Field of the `article:{id}` hash holding the version of the article's
+/// current edges, written with every edge of that version. Entries of an
+/// older version were retracted when the article was updated.
+pub const EDGES_VERSION: &str = "edges_version";
+
+/// Occurrences of one synonym of a concept in one article. Counted per
+/// concept in the `concept_synonyms:{graph}:{concept}` hash and recorded in
+/// `article_synonyms:{article}` so they can be retracted like contributions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SynonymCount {
+    pub graph: String,
+    pub concept: String,
+    pub synonym: String,
+    pub count: i64,
+}
+
+impl SynonymCount {
+    /// Hash field identifying the synonym: `{graph}|{concept}|{synonym}`,
+    /// terms may contain `:`
+    pub fn field(&self) -> String {
+        format!("{}|{}|{}", self.graph, self.concept, self.synonym)
+    }
+
+    /// Inverse of `field`
+    pub fn parse(field: &str, count: i64) -> Option<Self> {
+        let mut parts = field.splitn(3, '|');
+        Some(SynonymCount {
+            graph: parts.next()?.to_string(),
+            concept: parts.next()?.to_string(),
+            synonym: parts.next()?.to_string(),
+            count,
+        })
+    }
+}
+
+/// A sentence of an article, kept as evidence of the edges found in it.
+/// Stored as a JSON string so the article index, which indexes the
+/// `article:` hashes, leaves it out.
+pub fn sentence_key(article: &str, n: usize) -> String {
+    format!("article:{article}:sentence:{n}")
+}
+
+/// Numbers of the sentences of `article` an edge was found in, scored by
+/// the rank the edge got in each
+pub fn edge_evidence_key(source: &str, destination: &str, article: &str) -> String {
+    format!("edge_evidence:{source}:{destination}:{article}")
+}
+
+pub fn article_synonyms_key(article: &str) -> String {
+    format!("article_synonyms:{article}")
+}
+
+pub fn concept_synonyms_key(graph: &str, concept: &str) -> String {
+    format!("concept_synonyms:{graph}:{concept}")
+}
+
+/// Quotes `value` as a Cypher string literal.
+pub fn cypher_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Builds a single GRAPH.QUERY merging every edge of the batch:
+/// `(:entity {id})-[:{relation} {year}]->(:entity {id})`, where repeated
+/// occurrences add up their rank. Nodes are named by the normalised term
+/// and collect the synonyms seen for them in `synonyms`. The edges have to
+/// share `relation`, see `by_relation`.
+pub fn merge_edges_query(relation: &str, edges: &[EdgeRecord]) -> String {
+    let edges: Vec<String> = edges
+        .iter()
+        .map(|edge| {
+            format!(
+                "{{source:{},destination:{},source_name:{},destination_name:{},source_synonym:{},destination_synonym:{},rank:{:?},year:{}}}",
+                cypher_string(&edge.source),
+                cypher_string(&edge.destination),
+                cypher_string(&edge.source_name),
+                cypher_string(&edge.destination_name),
+                cypher_option(edge.source_synonym.as_deref()),
+                cypher_option(edge.destination_synonym.as_deref()),
+                edge.rank,
+                edge.year
+            )
+        })
+        .collect();
+    format!(
+        "CYPHER edges=[{}] UNWIND $edges AS edge \
+         MERGE (e:entity {{id: edge.source}}) \
+         SET e.name = edge.source_name, e.synonyms = {} \
+         MERGE (t:entity {{id: edge.destination}}) \
+         SET t.name = edge.destination_name, t.synonyms = {} \
+         MERGE (e)-[r:{relation} {{year: edge.year}}]->(t) \
+         ON CREATE SET r.rank = edge.rank ON MATCH SET r.rank = r.rank + edge.rank",
+        edges.join(","),
+        add_synonym("e", "edge.source_synonym"),
+        add_synonym("t", "edge.destination_synonym"),
+    )
+}
+
+/// `value` as a Cypher string literal, `null` when missing
+fn cypher_option(value: Option<&str>) -> String {
+    value
+        .map(cypher_string)
+        .unwrap_or_else(|| "null".to_string())
+}
+
+/// Cypher expression adding `synonym` to the `synonyms` list of `node`
+/// unless it's already there or null. Nodes merged before synonyms were
+/// tracked have no list yet.
+fn add_synonym(node: &str, synonym: &str) -> String {
+    format!(
+        "CASE WHEN {synonym} IS NULL OR {synonym} IN coalesce({node}.synonyms, []) THEN {node}.synonyms \
+         ELSE coalesce({node}.synonyms, []) + {synonym} END"
+    )
+}
+
+/// Builds a single GRAPH.QUERY subtracting each contribution from its
+/// relationship rank, deleting relationships left without support. The
+/// contributions have to share `relation`.
+pub fn retract_edges_query(relation: &str, contributions: &[Contribution]) -> String {
+    let edges: Vec<String> = contributions
+        .iter()
+        .map(|c| {
+            format!(
+                "{{source:{},destination:{},rank:{:?},year:{}}}",
+                cypher_string(&c.source),
+                cypher_string(&c.destination),
+                c.rank,
+                c.year
+            )
+        })
+        .collect();
+    format!(
+        "CYPHER edges=[{}] UNWIND $edges AS edge \
+         MATCH (e:entity {{id: edge.source}})-[r:{relation} {{year: edge.year}}]->(t:entity {{id: edge.destination}}) \
+         SET r.rank = r.rank - edge.rank \
+         WITH r WHERE r.rank <= 0 DELETE r",
+        edges.join(",")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_edges_query() {
+        let edges = vec![EdgeRecord {
+            source: "01H6VGEFEAVH6ZN4G5TGZZ21RB".to_string(),
+            destination: "01H6VGEFE84JW9045V7F5WFXGY".to_string(),
+            source_name: "strategy documents".to_string(),
+            destination_name: "the \"project\" scheduling".to_string(),
+            source_synonym: Some("swot".to_string()),
+            destination_synonym: Some("scheduling".to_string()),
+            rank: 1.0,
+            year: 2023,
+            relation: CO_OCCURS.to_string(),
+            sentence: Some(0),
+        }];
+        let query = merge_edges_query(CO_OCCURS, &edges);
+        assert!(query.starts_with("CYPHER edges=[{source:\"01H6VGEFEAVH6ZN4G5TGZZ21RB\","));
+        assert!(query.contains("source_name:\"strategy documents\","));
+        assert!(query.contains("destination_synonym:\"scheduling\",rank:1.0,year:2023}]"));
+        assert!(query.contains("destination_name:\"the \\\"project\\\" scheduling\","));
+        assert!(query.contains(
+            "WHEN edge.source_synonym IS NULL OR edge.source_synonym IN coalesce(e.synonyms, [])"
+        ));
+        assert!(query.contains("-[r:CO_OCCURS {year: edge.year}]->"));
+
+        let link = EdgeRecord {
+            source_synonym: None,
+            destination_synonym: None,
+            relation: LINKS_TO.to_string(),
+            sentence: None,
+            ..edges[0].clone()
+        };
+        let query = merge_edges_query(LINKS_TO, &[link]);
+        assert!(query.contains("source_synonym:null,destination_synonym:null,"));
+    }
+
+    #[test]
+    fn test_contribution_field_roundtrip() {
+        let contribution = Contribution {
+            graph: "graph:project-manager".to_string(),
+            source: "01H6VGEFEAVH6ZN4G5TGZZ21RB".to_string(),
+            destination: "01H6VGEFE84JW9045V7F5WFXGY".to_string(),
+            year: 2023,
+            rank: 2.0,
+            relation: CO_OCCURS.to_string(),
+        };
+        let parsed = Contribution::parse(&contribution.field(), 2.0).unwrap();
+        assert_eq!(parsed, contribution);
+        assert_eq!(Contribution::parse("no-year", 1.0), None);
+
+        let link = Contribution {
+            relation: LINKS_TO.to_string(),
+            ..contribution
+        };
+        assert_eq!(Contribution::parse(&link.field(), 2.0).unwrap(), link);
+
+        // note ids are paths, which may contain the old `:` delimiter
+        let note = Contribution {
+            destination: "meetings/2023:retro".to_string(),
+            ..link
+        };
+        assert_eq!(Contribution::parse(&note.field(), 2.0).unwrap(), note);
+    }
+
+    #[test]
+    fn test_contribution_parse_colon_field() {
+        let parsed = Contribution::parse("graph:project-manager:a:b:2023:LINKS_TO", 1.0).unwrap();
+        assert_eq!(parsed.graph, "graph:project-manager");
+        assert_eq!(parsed.source, "a");
+        assert_eq!(parsed.destination, "b");
+        assert_eq!(parsed.year, 2023);
+        assert_eq!(parsed.relation, LINKS_TO);
+        let parsed = Contribution::parse("graph:project-manager:a:b:2023", 1.0).unwrap();
+        assert_eq!(parsed.relation, CO_OCCURS);
+    }
+
+    #[test]
+    fn test_synonym_count_field_roundtrip() {
+        let count = SynonymCount {
+            graph: "graph:project-manager".to_string(),
+            concept: "01H6VGEFEAVH6ZN4G5TGZZ21RB".to_string(),
+            synonym: "swot | strengths".to_string(),
+            count: 3,
+        };
+        assert_eq!(SynonymCount::parse(&count.field(), 3).unwrap(), count);
+        assert_eq!(SynonymCount::parse("no-concept", 1), None);
+    }
+}
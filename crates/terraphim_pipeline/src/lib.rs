@@ -1,15 +1,41 @@
 use regex::Regex;
 use unicode_segmentation::UnicodeSegmentation;
 
+pub mod graph;
+pub mod shard;
+pub mod worker;
+
 #[macro_use]
 extern crate lazy_static;
 lazy_static! {
     static ref RE: Regex = Regex::new(r"[?!|]\s+").unwrap();
 }
 pub fn split_paragraphs(paragraphs: &str) -> Vec<&str> {
-    let sentences = UnicodeSegmentation::split_sentence_bounds(paragraphs);
-    let parts = sentences.flat_map(|sentence| RE.split(sentence.trim()));
-    parts.map(|part| part.trim()).collect()
+    split_paragraph_offsets(paragraphs)
+        .into_iter()
+        .map(|(_, part)| part)
+        .collect()
+}
+
+/// The sentences of `split_paragraphs` with their byte offset in `paragraphs`
+pub fn split_paragraph_offsets(paragraphs: &str) -> Vec<(usize, &str)> {
+    let mut parts = Vec::new();
+    for (start, sentence) in paragraphs.split_sentence_bound_indices() {
+        let trimmed = sentence.trim();
+        let start = start + sentence.len() - sentence.trim_start().len();
+        let mut last = 0;
+        let ends = RE
+            .find_iter(trimmed)
+            .map(|m| (m.start(), m.end()))
+            .chain(std::iter::once((trimmed.len(), trimmed.len())));
+        for (end, next) in ends {
+            let part = &trimmed[last..end];
+            let lead = part.len() - part.trim_start().len();
+            parts.push((start + last + lead, part.trim()));
+            last = next;
+        }
+    }
+    parts
 }
 
 #[cfg(test)]
@@ -24,4 +50,18 @@ mod tests {
         }
         // assert_eq!(result, 4);
     }
+
+    #[test]
+    fn test_split_paragraph_offsets() {
+        let paragraph = "The swot is done.\n Then project scheduling starts? Yes | no!  Done";
+        let parts = split_paragraph_offsets(paragraph);
+        assert_eq!(
+            parts.iter().map(|(_, part)| *part).collect::<Vec<_>>(),
+            split_paragraphs(paragraph)
+        );
+        assert!(parts.iter().any(|(_, part)| *part == "Yes"));
+        for (offset, part) in parts {
+            assert_eq!(&paragraph[offset..offset + part.len()], part);
+        }
+    }
 }
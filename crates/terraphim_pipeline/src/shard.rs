@@ -0,0 +1,146 @@
+use std::str::FromStr;
+
+use crate::graph::EdgeRecord;
+
+/// What decides the `edges_matched_*` stream an edge is written to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShardStrategy {
+    /// Hash of the source concept, edges of a concept stay in order
+    Source,
+    /// Hash of the article id, edges of an article stay together
+    Article,
+    /// Hash of the role, one stream per role
+    Role,
+}
+
+impl FromStr for ShardStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "source" => Ok(ShardStrategy::Source),
+            "article" => Ok(ShardStrategy::Article),
+            "role" => Ok(ShardStrategy::Role),
+            s => Err(format!(
+                "unknown shard strategy {s}, expected source, article or role"
+            )),
+        }
+    }
+}
+
+/// Spreads the edge streams of a role over `count` shards. Each shard of
+/// each role has its own hash tag so the streams land in different Redis
+/// Cluster slots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sharding {
+    pub strategy: ShardStrategy,
+    pub count: u32,
+}
+
+impl Sharding {
+    pub fn new(strategy: &str, count: u32) -> Result<Self, String> {
+        if count == 0 {
+            return Err("shard count must be at least 1".to_string());
+        }
+        Ok(Sharding {
+            strategy: strategy.parse()?,
+            count,
+        })
+    }
+
+    /// Shard of an edge found in `article` for `role`
+    pub fn shard(&self, role: &str, article: &str, edge: &EdgeRecord) -> u32 {
+        let key = match self.strategy {
+            ShardStrategy::Source => &edge.source,
+            ShardStrategy::Article => article,
+            ShardStrategy::Role => role,
+        };
+        (fnv1a(key) % u64::from(self.count)) as u32
+    }
+
+    /// Stream of an edge found in `article` for `role`
+    pub fn stream(&self, role: &str, article: &str, edge: &EdgeRecord) -> String {
+        stream_name(role, self.shard(role, article, edge), self.count)
+    }
+
+    /// Every stream of `role`, for readers fanning out over the shards
+    pub fn streams(&self, role: &str) -> Vec<String> {
+        (0..self.count)
+            .map(|shard| stream_name(role, shard, self.count))
+            .collect()
+    }
+}
+
+/// Stream of a single shard role, as named before edge streams were
+/// sharded, so entries written before are still read
+const UNSHARDED_TAG: &str = "{06S}";
+
+/// `edges_matched_{role_NN}` for shard `shard` of `count`, the braces make
+/// role and shard the hash tag. A single shard keeps the older name
+/// `edges_matched_{role}_{06S}`, whose tag all such roles share.
+pub fn stream_name(role: &str, shard: u32, count: u32) -> String {
+    if count == 1 {
+        return format!("edges_matched_{role}_{UNSHARDED_TAG}");
+    }
+    format!("edges_matched_{{{role}_{shard:02}}}")
+}
+
+/// FNV-1a, stable across builds and platforms unlike `DefaultHasher`,
+/// writers and readers have to agree on the shard of a key
+fn fnv1a(key: &str) -> u64 {
+    key.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::CO_OCCURS;
+
+    fn edge(source: &str) -> EdgeRecord {
+        EdgeRecord {
+            source: source.to_string(),
+            destination: "01H6VGEFE84JW9045V7F5WFXGY".to_string(),
+            source_name: "strategy documents".to_string(),
+            destination_name: "project planning".to_string(),
+            source_synonym: Some("swot".to_string()),
+            destination_synonym: Some("project scheduling".to_string()),
+            rank: 1.0,
+            year: 2023,
+            relation: CO_OCCURS.to_string(),
+            sentence: None,
+        }
+    }
+
+    #[test]
+    fn test_sharding() {
+        assert!(Sharding::new("source", 0).is_err());
+        assert!(Sharding::new("random", 4).is_err());
+
+        let sharding = Sharding::new("source", 8).unwrap();
+        let shards: std::collections::HashSet<u32> = (0..64)
+            .map(|i| sharding.shard("pm", "a1", &edge(&format!("concept-{i}"))))
+            .collect();
+        assert!(shards.len() > 1 && shards.iter().all(|s| *s < 8));
+        // the same source always goes to the same stream
+        assert_eq!(
+            sharding.stream("pm", "a1", &edge("c")),
+            sharding.stream("pm", "a2", &edge("c"))
+        );
+
+        let by_role = Sharding::new("role", 1).unwrap();
+        assert_eq!(
+            by_role.stream("pm", "a1", &edge("c")),
+            "edges_matched_pm_{06S}"
+        );
+        assert_eq!(
+            Sharding::new("article", 3).unwrap().streams("pm"),
+            vec![
+                "edges_matched_{pm_00}",
+                "edges_matched_{pm_01}",
+                "edges_matched_{pm_02}"
+            ]
+        );
+    }
+}
@@ -0,0 +1,549 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use redis::streams::{
+    StreamClaimReply, StreamId, StreamPendingCountReply, StreamReadOptions, StreamReadReply,
+};
+use redis::{from_redis_value, Cmd, Commands, ConnectionLike, RedisError, RedisResult};
+
+use crate::graph::{
+    article_edges_key, by_relation, edge_evidence_key, merge_edges_query, valid_relation,
+    Contribution, EdgeRecord, CO_OCCURS, EDGES_VERSION, LINKS_TO,
+};
+
+/// An edge read from an `edges_matched_*` stream entry.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchedEdge {
+    /// Graph of the role which extracted the edge
+    pub graph: String,
+    /// Article the edge was found in
+    pub article: String,
+    /// Version of the article's edges, see `EDGES_VERSION`
+    pub version: Option<String>,
+    pub edge: EdgeRecord,
+}
+
+impl MatchedEdge {
+    /// Parses a stream entry written by `parse_article`, `None` when a field
+    /// is missing. Entries without `relation` predate typed edges and co-occur,
+    /// co-occurrences without synonyms were named by the matched term. Links and
+    /// older entries have no `sentence`, older entries no `version`.
+    pub fn from_stream_id(entry: &StreamId) -> Option<Self> {
+        let relation: String = entry
+            .get("relation")
+            .unwrap_or_else(|| CO_OCCURS.to_string());
+        if !valid_relation(&relation) {
+            return None;
+        }
+        let source_name: String = entry.get("source_name")?;
+        let destination_name: String = entry.get("destination_name")?;
+        let synonym = |field: &str, name: &String| match relation.as_str() {
+            LINKS_TO => None,
+            _ => entry.get(field).or_else(|| Some(name.clone())),
+        };
+        Some(MatchedEdge {
+            graph: entry.get("graph")?,
+            article: entry.get("article")?,
+            version: entry.get("version"),
+            edge: EdgeRecord {
+                source: entry.get("source")?,
+                destination: entry.get("destination")?,
+                source_synonym: synonym("source_synonym", &source_name),
+                destination_synonym: synonym("destination_synonym", &destination_name),
+                source_name,
+                destination_name,
+                rank: entry.get("rank")?,
+                year: entry.get("year")?,
+                relation,
+                sentence: entry.get("sentence"),
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct WorkerConfig {
+    /// Consumer group shared by all workers
+    pub group: String,
+    /// Name of this worker in the group, keep it stable across restarts
+    pub consumer: String,
+    pub batch_size: usize,
+    pub block_ms: usize,
+    /// Entries pending longer than this are reclaimed and retried, this is
+    /// also the backoff between two attempts at a failing entry
+    pub min_idle_ms: usize,
+    /// Entries delivered this many times are moved to the dead-letter stream
+    pub max_deliveries: usize,
+    /// How often streams matching the discovery pattern are looked up again
+    pub rescan_ms: usize,
+}
+
+/// Stream failing entries of `stream` are moved to, with the fields of the
+/// entry plus `dead_stream` and `dead_id` naming where it came from
+pub fn dead_letter_stream(stream: &str) -> String {
+    format!("edges_dead:{stream}")
+}
+
+/// Consumes the edge streams through a consumer group, merging every batch
+/// into the graph and `edges_scored:*` before acknowledging it.
+/// Delivery is at-least-once: a crash between the writes and XACK replays the batch.
+/// An entry whose writes fail stays pending and is retried after `min_idle_ms`,
+/// up to `max_deliveries` times before it goes to its `dead_letter_stream`.
+/// Works on a standalone connection as well as on a cluster connection.
+pub struct EdgeWorker<C: ConnectionLike> {
+    con: C,
+    config: WorkerConfig,
+    streams: Vec<String>,
+    /// SCAN pattern of streams to pick up while running
+    pattern: Option<String>,
+    scanned: Instant,
+}
+
+impl<C: ConnectionLike> EdgeWorker<C> {
+    pub fn new(con: C, config: WorkerConfig, streams: Vec<String>) -> RedisResult<Self> {
+        let mut worker = EdgeWorker {
+            con,
+            config,
+            streams: Vec::new(),
+            pattern: None,
+            scanned: Instant::now(),
+        };
+        worker.add_streams(streams)?;
+        Ok(worker)
+    }
+
+    /// Also consumes streams matching `pattern` which appear later, such as
+    /// the streams of a new role. SCAN only sees the keys of one node, so
+    /// this is for standalone servers.
+    pub fn discover(mut self, pattern: &str) -> Self {
+        self.pattern = Some(pattern.to_string());
+        self
+    }
+
+    fn add_streams(&mut self, streams: Vec<String>) -> RedisResult<()> {
+        for stream in streams {
+            if self.streams.contains(&stream) {
+                continue;
+            }
+            let created: RedisResult<()> =
+                self.con
+                    .xgroup_create_mkstream(&stream, &self.config.group, "0");
+            match created {
+                Ok(()) => println!("Created group {} on {}", self.config.group, stream),
+                Err(e) if e.code() == Some("BUSYGROUP") => {}
+                Err(e) => return Err(e),
+            }
+            self.streams.push(stream);
+        }
+        Ok(())
+    }
+
+    fn rescan(&mut self) -> RedisResult<()> {
+        let pattern = match &self.pattern {
+            Some(pattern) => pattern.clone(),
+            None => return Ok(()),
+        };
+        if self.scanned.elapsed() < Duration::from_millis(self.config.rescan_ms as u64) {
+            return Ok(());
+        }
+        self.scanned = Instant::now();
+        let found: Vec<String> = self.con.scan_match(&pattern)?.collect();
+        let new: Vec<String> = found
+            .into_iter()
+            .filter(|stream| !self.streams.contains(stream))
+            .collect();
+        if !new.is_empty() {
+            println!("Consuming new streams {:?}", new);
+            self.add_streams(new)?;
+        }
+        Ok(())
+    }
+
+    pub fn run(&mut self) -> RedisResult<()> {
+        if self.streams.is_empty() && self.pattern.is_none() {
+            println!("No streams to consume");
+            return Ok(());
+        }
+        self.drain_pending()?;
+        loop {
+            self.rescan()?;
+            self.reclaim()?;
+            self.read_new()?;
+        }
+    }
+
+    /// Replays entries delivered to this consumer but never acknowledged,
+    /// which is how a restarted worker resumes where it stopped. Entries
+    /// failing again stay pending, so the history is paged by id.
+    fn drain_pending(&mut self) -> RedisResult<()> {
+        for stream in self.streams.clone() {
+            let mut start = "0".to_string();
+            loop {
+                let options = StreamReadOptions::default()
+                    .group(&self.config.group, &self.config.consumer)
+                    .count(self.config.batch_size);
+                let reply: StreamReadReply =
+                    self.con.xread_options(&[&stream], &[&start], &options)?;
+                let ids: Vec<StreamId> = reply.keys.into_iter().flat_map(|key| key.ids).collect();
+                match ids.last() {
+                    Some(last) => start = last.id.clone(),
+                    None => break,
+                }
+                self.process(&stream, &ids)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads every stream on its own, the shards of a role live in different
+    /// cluster slots and can't be read with one command. The wait for new
+    /// entries is split between the streams.
+    fn read_new(&mut self) -> RedisResult<()> {
+        if self.streams.is_empty() {
+            std::thread::sleep(Duration::from_millis(self.config.block_ms as u64));
+            return Ok(());
+        }
+        let block_ms = (self.config.block_ms / self.streams.len()).max(1);
+        for stream in self.streams.clone() {
+            let options = StreamReadOptions::default()
+                .group(&self.config.group, &self.config.consumer)
+                .count(self.config.batch_size)
+                .block(block_ms);
+            let reply: StreamReadReply = self.con.xread_options(&[&stream], &[">"], &options)?;
+            for key in reply.keys {
+                self.process(&key.key, &key.ids)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Takes over entries left pending by consumers which went away, and
+    /// retries entries which failed here. Entries delivered `max_deliveries`
+    /// times are moved to the dead-letter stream instead.
+    fn reclaim(&mut self) -> RedisResult<()> {
+        for stream in self.streams.clone() {
+            let mut start = "-".to_string();
+            loop {
+                let pending: StreamPendingCountReply = self.con.xpending_count(
+                    &stream,
+                    &self.config.group,
+                    &start,
+                    "+",
+                    self.config.batch_size,
+                )?;
+                let page = pending.ids.len();
+                match pending.ids.last() {
+                    Some(last) => start = next_id(&last.id),
+                    None => break,
+                }
+                let (dead, retry): (Vec<_>, Vec<_>) = pending
+                    .ids
+                    .into_iter()
+                    .filter(|p| p.last_delivered_ms >= self.config.min_idle_ms)
+                    .partition(|p| p.times_delivered >= self.config.max_deliveries);
+                let dead: Vec<String> = dead.into_iter().map(|p| p.id).collect();
+                let retry: Vec<String> = retry.into_iter().map(|p| p.id).collect();
+                if !dead.is_empty() {
+                    let claimed = self.claim(&stream, &dead)?;
+                    self.dead_letter(&stream, &claimed.ids)?;
+                }
+                if !retry.is_empty() {
+                    let claimed = self.claim(&stream, &retry)?;
+                    println!("Reclaimed {} entries from {}", claimed.ids.len(), stream);
+                    self.process(&stream, &claimed.ids)?;
+                }
+                if page < self.config.batch_size {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn claim(&mut self, stream: &str, ids: &[String]) -> RedisResult<StreamClaimReply> {
+        self.con.xclaim(
+            stream,
+            &self.config.group,
+            &self.config.consumer,
+            self.config.min_idle_ms,
+            ids,
+        )
+    }
+
+    /// Copies entries to the dead-letter stream and acknowledges them
+    fn dead_letter(&mut self, stream: &str, entries: &[StreamId]) -> RedisResult<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let dead = dead_letter_stream(stream);
+        for entry in entries {
+            let mut cmd = redis::cmd("XADD");
+            cmd.arg(&dead).arg("*");
+            for (field, value) in entry.map.iter() {
+                if let Ok(value) = from_redis_value::<Vec<u8>>(value) {
+                    cmd.arg(field).arg(value);
+                }
+            }
+            cmd.arg("dead_stream")
+                .arg(stream)
+                .arg("dead_id")
+                .arg(&entry.id);
+            cmd.query::<String>(&mut self.con)?;
+        }
+        let ids: Vec<&str> = entries.iter().map(|entry| entry.id.as_str()).collect();
+        let _: usize = self.con.xack(stream, &self.config.group, &ids)?;
+        println!("Moved {} entries from {} to {}", ids.len(), stream, dead);
+        Ok(())
+    }
+
+    /// Version of the edges `article` currently has: `None` when it was
+    /// deleted, `Some(None)` when it was stored before edges had versions.
+    fn article_version(&mut self, article: &str) -> RedisResult<Option<Option<String>>> {
+        let key = format!("article:{article}");
+        let exists: bool = self.con.exists(&key)?;
+        if !exists {
+            return Ok(None);
+        }
+        Ok(Some(self.con.hget(&key, EDGES_VERSION)?))
+    }
+
+    fn process(&mut self, stream: &str, entries: &[StreamId]) -> RedisResult<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let mut versions: HashMap<String, Option<Option<String>>> = HashMap::new();
+        // acknowledged below: applied, or skipped so they can't block the group
+        let mut done: Vec<&str> = Vec::new();
+        let mut current: Vec<(&str, MatchedEdge)> = Vec::new();
+        for entry in entries {
+            let matched = match MatchedEdge::from_stream_id(entry) {
+                Some(matched) => matched,
+                None => {
+                    println!("Skipping malformed entry {} in {}", entry.id, stream);
+                    done.push(&entry.id);
+                    continue;
+                }
+            };
+            if !versions.contains_key(&matched.article) {
+                let version = self.article_version(&matched.article)?;
+                versions.insert(matched.article.clone(), version);
+            }
+            // the article was deleted or updated since, and its edges retracted
+            if !is_current(&matched, &versions[&matched.article]) {
+                println!("Skipping stale entry {} in {}", entry.id, stream);
+                done.push(&entry.id);
+                continue;
+            }
+            current.push((&entry.id, matched));
+        }
+        let batch: Vec<MatchedEdge> = current.iter().map(|(_, m)| m.clone()).collect();
+        match self.apply(&batch) {
+            Ok(()) => done.extend(current.iter().map(|(id, _)| *id)),
+            Err(e) if is_connection_error(&e) => return Err(e),
+            // find the failing entries, the others are applied again which
+            // at-least-once delivery allows
+            Err(e) => {
+                println!(
+                    "Batch from {} failed, retrying entry by entry: {}",
+                    stream, e
+                );
+                for (id, matched) in current.iter() {
+                    match self.apply(std::slice::from_ref(matched)) {
+                        Ok(()) => done.push(*id),
+                        Err(e) if is_connection_error(&e) => return Err(e),
+                        Err(e) => {
+                            println!("Entry {} in {} failed, left pending: {}", id, stream, e)
+                        }
+                    }
+                }
+            }
+        }
+        if !done.is_empty() {
+            let _: usize = self.con.xack(stream, &self.config.group, &done)?;
+        }
+        println!(
+            "Processed {} of {} entries from {}",
+            done.len(),
+            entries.len(),
+            stream
+        );
+        Ok(())
+    }
+
+    /// Merges the edges into their graphs and `edges_scored:*`
+    fn apply(&mut self, matched: &[MatchedEdge]) -> RedisResult<()> {
+        let mut by_graph: HashMap<&str, Vec<&MatchedEdge>> = HashMap::new();
+        for m in matched {
+            by_graph.entry(&m.graph).or_default().push(m);
+        }
+        for (graph, matched) in by_graph.iter() {
+            let edges: Vec<EdgeRecord> = matched.iter().map(|m| m.edge.clone()).collect();
+            for (relation, edges) in by_relation(&edges) {
+                redis::cmd("GRAPH.QUERY")
+                    .arg(graph)
+                    .arg(merge_edges_query(relation, &edges))
+                    .query::<redis::Value>(&mut self.con)?;
+            }
+            let mut cmds = Vec::new();
+            for m in matched {
+                cmds.push(Cmd::zincr(
+                    format!("edges_scored:{}:{}", m.edge.source, m.edge.destination),
+                    &m.article,
+                    m.edge.rank,
+                ));
+                let mut cmd = redis::cmd("HINCRBYFLOAT");
+                cmd.arg(article_edges_key(&m.article))
+                    .arg(Contribution::new(graph, &m.edge).field())
+                    .arg(m.edge.rank);
+                cmds.push(cmd);
+                if let Some(n) = m.edge.sentence {
+                    cmds.push(Cmd::zincr(
+                        edge_evidence_key(&m.edge.source, &m.edge.destination, &m.article),
+                        n,
+                        m.edge.rank,
+                    ));
+                }
+            }
+            run_all(&mut self.con, cmds)?;
+        }
+        Ok(())
+    }
+}
+
+/// Errors of the connection rather than of an entry, the worker stops on
+/// them instead of retrying entries against a dead connection
+fn is_connection_error(e: &RedisError) -> bool {
+    e.is_io_error() || e.is_connection_dropped() || e.is_connection_refusal() || e.is_timeout()
+}
+
+/// Smallest stream id after `id`, so pages of XPENDING don't overlap
+fn next_id(id: &str) -> String {
+    let next = id
+        .split_once('-')
+        .and_then(|(ms, seq)| Some((ms, seq.parse::<u64>().ok()?.checked_add(1)?)));
+    match next {
+        Some((ms, seq)) => format!("{ms}-{seq}"),
+        // exclusive range, Redis 6.2 and later
+        None => format!("({id}"),
+    }
+}
+
+/// Whether the edge still belongs to its article, given the article's
+/// current version from `EdgeWorker::article_version`
+fn is_current(matched: &MatchedEdge, version: &Option<Option<String>>) -> bool {
+    match (version, &matched.version) {
+        (None, _) => false,
+        (Some(current), Some(version)) => current.as_ref() == Some(version),
+        // written before edges had versions
+        (Some(_), None) => true,
+    }
+}
+
+/// Runs `cmds` in one pipeline, or one by one on connections which can't
+/// pipeline, such as a cluster connection where the keys live on different nodes.
+fn run_all<C: ConnectionLike>(con: &mut C, cmds: Vec<Cmd>) -> RedisResult<()> {
+    if con.supports_pipelining() {
+        let mut pipe = redis::pipe();
+        for cmd in cmds {
+            pipe.add_command(cmd).ignore();
+        }
+        return pipe.query(con);
+    }
+    for cmd in cmds {
+        cmd.query::<redis::Value>(con)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::Value;
+
+    #[test]
+    fn test_matched_edge_from_stream_id() {
+        let mut entry = StreamId {
+            id: "1690000000000-0".to_string(),
+            map: HashMap::new(),
+        };
+        for (key, value) in [
+            ("graph", "graph_project-manager"),
+            ("article", "01H6VGEFEAVH6ZN4G5TGZZ21RA"),
+            ("source", "01H6VGEFEAVH6ZN4G5TGZZ21RB"),
+            ("destination", "01H6VGEFE84JW9045V7F5WFXGY"),
+            ("source_name", "strategy documents"),
+            ("destination_name", "project scheduling"),
+            ("source_synonym", "swot"),
+            ("rank", "1"),
+        ] {
+            entry
+                .map
+                .insert(key.to_string(), Value::Data(value.as_bytes().to_vec()));
+        }
+        // year missing
+        assert_eq!(MatchedEdge::from_stream_id(&entry), None);
+
+        entry
+            .map
+            .insert("year".to_string(), Value::Data(b"2023".to_vec()));
+        let matched = MatchedEdge::from_stream_id(&entry).unwrap();
+        assert_eq!(matched.graph, "graph_project-manager");
+        assert_eq!(matched.edge.rank, 1.0);
+        assert_eq!(matched.edge.year, 2023);
+        assert_eq!(matched.edge.relation, CO_OCCURS);
+        assert_eq!(matched.edge.source_synonym.as_deref(), Some("swot"));
+        // entries written before synonyms were tracked
+        assert_eq!(
+            matched.edge.destination_synonym.as_deref(),
+            Some("project scheduling")
+        );
+        assert_eq!(matched.edge.sentence, None);
+        assert_eq!(matched.version, None);
+    }
+
+    #[test]
+    fn test_next_id() {
+        assert_eq!(next_id("1690000000000-0"), "1690000000000-1");
+        assert_eq!(next_id("1690000000000-9"), "1690000000000-10");
+        assert_eq!(
+            next_id("1690000000000-18446744073709551615"),
+            "(1690000000000-18446744073709551615"
+        );
+    }
+
+    #[test]
+    fn test_is_current() {
+        let mut entry = StreamId {
+            id: "1690000000000-0".to_string(),
+            map: HashMap::new(),
+        };
+        for (key, value) in [
+            ("graph", "graph_project-manager"),
+            ("article", "a1"),
+            ("source", "s"),
+            ("destination", "d"),
+            ("source_name", "swot"),
+            ("destination_name", "project scheduling"),
+            ("rank", "1"),
+            ("year", "2023"),
+            ("version", "v2"),
+        ] {
+            entry
+                .map
+                .insert(key.to_string(), Value::Data(value.as_bytes().to_vec()));
+        }
+        let matched = MatchedEdge::from_stream_id(&entry).unwrap();
+        assert!(is_current(&matched, &Some(Some("v2".to_string()))));
+        // updated since
+        assert!(!is_current(&matched, &Some(Some("v3".to_string()))));
+        // deleted, or deleted and being stored again
+        assert!(!is_current(&matched, &None));
+        assert!(!is_current(&matched, &Some(None)));
+        let unversioned = MatchedEdge {
+            version: None,
+            ..matched
+        };
+        assert!(is_current(&unversioned, &Some(None)));
+        assert!(!is_current(&unversioned, &None));
+    }
+}
@@ -0,0 +1,120 @@
+use poem_openapi::Object;
+use terraphim_automata::Matched;
+
+/// A concept found in annotated text
+#[derive(Debug, Clone, PartialEq, Object)]
+pub struct ConceptSpan {
+    /// Byte offset of the first matched byte
+    pub start: usize,
+    /// Byte offset after the last matched byte
+    pub end: usize,
+    /// Text as it appears in the document
+    pub text: String,
+    /// Thesaurus term which matched
+    pub term: String,
+    /// Normalised term of the concept
+    pub nterm: String,
+    /// Concept id
+    pub id: String,
+}
+
+/// Turns matches into spans ordered by position. Matches without a position,
+/// outside `text` or overlapping an earlier span are left out.
+pub fn spans(text: &str, matches: Vec<Matched>) -> Vec<ConceptSpan> {
+    let mut spans: Vec<ConceptSpan> = matches
+        .into_iter()
+        .filter_map(|m| {
+            let (start, end) = m.pos?;
+            let matched = text.get(start..end)?;
+            Some(ConceptSpan {
+                start,
+                end,
+                text: matched.to_string(),
+                term: m.term,
+                nterm: m.nterm,
+                id: m.id,
+            })
+        })
+        .collect();
+    spans.sort_by(|a, b| a.start.cmp(&b.start).then(b.end.cmp(&a.end)));
+    let mut end = 0;
+    spans.retain(|span| {
+        let keep = span.start >= end;
+        if keep {
+            end = span.end;
+        }
+        keep
+    });
+    spans
+}
+
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Renders `text` as HTML with every span linked to its concept.
+/// `link` is the concept url with `{id}` standing for the concept id.
+pub fn render_html(text: &str, spans: &[ConceptSpan], link: &str) -> String {
+    let mut html = String::with_capacity(text.len() * 2);
+    let mut offset = 0;
+    for span in spans {
+        html.push_str(&escape_html(&text[offset..span.start]));
+        html.push_str(&format!(
+            "<a class=\"concept\" href=\"{}\" data-concept-id=\"{}\" title=\"{}\">{}</a>",
+            escape_html(&link.replace("{id}", &span.id)),
+            escape_html(&span.id),
+            escape_html(&span.nterm),
+            escape_html(&span.text)
+        ));
+        offset = span.end;
+    }
+    html.push_str(&escape_html(&text[offset..]));
+    html.replace('\n', "<br>\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matched(term: &str, id: &str, pos: Option<(usize, usize)>) -> Matched {
+        Matched {
+            term: term.to_string(),
+            id: id.to_string(),
+            nterm: format!("{term} (n)"),
+            pos,
+        }
+    }
+
+    #[test]
+    fn test_spans_and_html() {
+        let text = "A <b>project manager</b> plans\nthe project.";
+        let matches = vec![
+            matched("project", "p", Some((35, 42))),
+            matched("project manager", "pm", Some((5, 20))),
+            // overlaps the longer match above
+            matched("manager", "m", Some((13, 20))),
+            matched("plans", "x", None),
+        ];
+        let spans = spans(text, matches);
+        let ids: Vec<&str> = spans.iter().map(|s| s.id.as_str()).collect();
+        assert_eq!(ids, vec!["pm", "p"]);
+        assert_eq!(spans[0].text, "project manager");
+
+        let html = render_html(text, &spans, "/concepts/{id}");
+        assert_eq!(
+            html,
+            "A &lt;b&gt;<a class=\"concept\" href=\"/concepts/pm\" data-concept-id=\"pm\" title=\"project manager (n)\">project manager</a>&lt;/b&gt; plans<br>\nthe <a class=\"concept\" href=\"/concepts/p\" data-concept-id=\"p\" title=\"project (n)\">project</a>."
+        );
+    }
+}
@@ -0,0 +1,146 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use arc_swap::ArcSwap;
+use terraphim_automata::{load_automata, Dictionary};
+use thiserror::Error;
+
+use crate::error::ApiError;
+use crate::roles::{Role, RoleRegistry};
+
+/// Thesaurus of a single role: surface term to concept.
+pub type Automata = HashMap<String, Dictionary>;
+
+#[derive(Debug, Error)]
+pub enum AutomataError {
+    #[error("automata for role `{role}` is not loaded")]
+    NotLoaded { role: String },
+    #[error("failed to load automata for role `{role}` from {url}: {message}")]
+    Load {
+        role: String,
+        url: String,
+        message: String,
+    },
+}
+
+/// Per role automata, loaded once and shared by all handlers.
+/// Readers take a snapshot `Arc`, so a reload never affects in-flight requests.
+#[derive(Default)]
+pub struct AutomataCache {
+    automata: ArcSwap<HashMap<String, Arc<Automata>>>,
+}
+
+impl AutomataCache {
+    /// Loads every role that declares an `automata_url`. Failures are logged
+    /// and the role is left out, so one broken url doesn't stop the server.
+    pub fn load(roles: &RoleRegistry) -> Self {
+        let cache = AutomataCache::default();
+        for err in cache.reload(roles) {
+            println!("{}", err);
+        }
+        cache
+    }
+
+    /// Automata of `role`. A role without `automata_url` is a config error,
+    /// a role whose automata failed to load may work after a reload.
+    pub fn get(&self, role: &Role) -> Result<Arc<Automata>, ApiError> {
+        role.automata_url()?;
+        let automata = self
+            .automata
+            .load()
+            .get(&role.shortname)
+            .cloned()
+            .ok_or_else(|| AutomataError::NotLoaded {
+                role: role.shortname.clone(),
+            })?;
+        Ok(automata)
+    }
+
+    /// Loaded role shortnames
+    pub fn roles(&self) -> Vec<String> {
+        let mut roles: Vec<String> = self.automata.load().keys().cloned().collect();
+        roles.sort();
+        roles
+    }
+
+    /// Re-reads every role's automata and swaps the whole map at once.
+    /// A role that fails to load keeps its previous automata.
+    pub fn reload(&self, roles: &RoleRegistry) -> Vec<AutomataError> {
+        let current = self.automata.load_full();
+        let mut automata = HashMap::new();
+        let mut errors = Vec::new();
+        for role in roles.iter() {
+            let url = match role.automata_url() {
+                Ok(url) => url,
+                Err(_) => continue,
+            };
+            match load_automata(url) {
+                Ok(loaded) => {
+                    automata.insert(role.shortname.clone(), Arc::new(loaded));
+                }
+                Err(e) => {
+                    errors.push(AutomataError::Load {
+                        role: role.shortname.clone(),
+                        url: url.to_string(),
+                        message: e.to_string(),
+                    });
+                    if let Some(previous) = current.get(&role.shortname) {
+                        automata.insert(role.shortname.clone(), previous.clone());
+                    }
+                }
+            }
+        }
+        self.automata.store(Arc::new(automata));
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roles::RoleError;
+
+    const ROLES: &str = r#"{"roles": {
+        "Project Manager": {
+            "shortname": "project-manager",
+            "name": "Project Manager",
+            "relevance_function": "rust",
+            "serverUrl": "/rsearch",
+            "automata_url": "./test-data/term_to_id.json"
+        },
+        "Broken": {
+            "name": "Broken",
+            "relevance_function": "rust",
+            "serverUrl": "/rsearch",
+            "automata_url": "./test-data/missing.json"
+        },
+        "Default": {
+            "name": "Default",
+            "relevance_function": "bm25",
+            "serverUrl": "/search"
+        }
+    }}"#;
+
+    #[test]
+    fn test_load_and_reload() {
+        let roles = RoleRegistry::from_json(ROLES).unwrap();
+        let cache = AutomataCache::load(&roles);
+        assert_eq!(cache.roles(), vec!["project-manager".to_string()]);
+        let before = cache.get(roles.get("project-manager").unwrap()).unwrap();
+        assert!(before.contains_key("swot"));
+        assert!(matches!(
+            cache.get(roles.get("broken").unwrap()),
+            Err(ApiError::Automata(AutomataError::NotLoaded { .. }))
+        ));
+        assert!(matches!(
+            cache.get(roles.get("default").unwrap()),
+            Err(ApiError::Role(RoleError::NoAutomata { .. }))
+        ));
+
+        let errors = cache.reload(&roles);
+        assert_eq!(errors.len(), 1);
+        let after = cache.get(roles.get("project-manager").unwrap()).unwrap();
+        // in-flight holders keep their snapshot, new readers see the new one
+        assert!(!Arc::ptr_eq(&before, &after));
+    }
+}
@@ -0,0 +1,202 @@
+use deadpool_redis::PoolError;
+use poem_openapi::{payload::Json, ApiResponse, Object};
+use redis::RedisError;
+use thiserror::Error;
+
+use crate::automata::AutomataError;
+use crate::roles::RoleError;
+
+/// Errors surfaced by the API handlers.
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error("redis is unavailable: {0}")]
+    RedisUnavailable(String),
+    #[error("search index is missing: {0}")]
+    IndexMissing(String),
+    #[error("invalid query: {0}")]
+    BadQuery(String),
+    #[error(transparent)]
+    Role(#[from] RoleError),
+    #[error(transparent)]
+    Automata(#[from] AutomataError),
+    #[error("failed to match concepts: {0}")]
+    Matching(String),
+    #[error("redis error: {0}")]
+    Redis(String),
+    #[error("internal error: {0}")]
+    Internal(String),
+}
+
+impl ApiError {
+    /// Stable, machine readable name of the error
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ApiError::RedisUnavailable(_) => "redis_unavailable",
+            ApiError::IndexMissing(_) => "index_missing",
+            ApiError::BadQuery(_) => "bad_query",
+            ApiError::Role(RoleError::Unknown { .. }) => "unknown_role",
+            ApiError::Role(RoleError::NoAutomata { .. }) => "role_without_automata",
+            ApiError::Role(_) => "role_config",
+            ApiError::Automata(_) => "automata_unavailable",
+            ApiError::Matching(_) => "matching_failed",
+            ApiError::Redis(_) => "redis_error",
+            ApiError::Internal(_) => "internal",
+        }
+    }
+
+    /// Whether sending the same request again later may succeed
+    pub fn retryable(&self) -> bool {
+        matches!(
+            self,
+            ApiError::RedisUnavailable(_) | ApiError::IndexMissing(_) | ApiError::Automata(_)
+        )
+    }
+}
+
+impl From<RedisError> for ApiError {
+    fn from(err: RedisError) -> Self {
+        let message = err.to_string();
+        // server errors are split into a code (first word) and the detail
+        let lower = format!(
+            "{} {} {}",
+            message,
+            err.code().unwrap_or_default(),
+            err.detail().unwrap_or_default()
+        )
+        .to_lowercase();
+        if err.is_io_error()
+            || err.is_connection_refusal()
+            || err.is_connection_dropped()
+            || err.is_timeout()
+        {
+            ApiError::RedisUnavailable(message)
+        } else if lower.contains("unknown index name") || lower.contains("no such index") {
+            ApiError::IndexMissing(message)
+        } else if lower.contains("syntax error") {
+            ApiError::BadQuery(message)
+        } else {
+            ApiError::Redis(message)
+        }
+    }
+}
+
+impl From<PoolError> for ApiError {
+    fn from(err: PoolError) -> Self {
+        match err {
+            PoolError::Backend(err) => err.into(),
+            err => ApiError::RedisUnavailable(err.to_string()),
+        }
+    }
+}
+
+/// JSON problem body returned with every error response
+#[derive(Debug, Object)]
+pub struct Problem {
+    kind: String,
+    message: String,
+    /// Whether retrying the same request later may succeed
+    retryable: bool,
+    /// Configured roles, set when the requested role is unknown
+    #[oai(skip_serializing_if_is_none)]
+    valid_roles: Option<Vec<String>>,
+}
+
+#[derive(ApiResponse)]
+pub enum ErrorResponse {
+    /// Returns when the request can't succeed as sent.
+    #[oai(status = 400)]
+    BadRequest(Json<Problem>),
+    /// Returns when Redis, the search index or an automata is unavailable.
+    #[oai(status = 503)]
+    ServiceUnavailable(Json<Problem>),
+    /// Returns on unexpected failures.
+    #[oai(status = 500)]
+    InternalError(Json<Problem>),
+}
+
+impl From<ApiError> for ErrorResponse {
+    fn from(err: ApiError) -> Self {
+        println!("Error {:?}", err);
+        let valid_roles = match &err {
+            ApiError::Role(RoleError::Unknown { valid, .. }) => Some(valid.clone()),
+            _ => None,
+        };
+        let problem = Json(Problem {
+            kind: err.kind().to_string(),
+            message: err.to_string(),
+            retryable: err.retryable(),
+            valid_roles,
+        });
+        match err {
+            ApiError::BadQuery(_)
+            | ApiError::Role(RoleError::Unknown { .. })
+            | ApiError::Role(RoleError::NoAutomata { .. }) => ErrorResponse::BadRequest(problem),
+            ApiError::RedisUnavailable(_) | ApiError::IndexMissing(_) | ApiError::Automata(_) => {
+                ErrorResponse::ServiceUnavailable(problem)
+            }
+            _ => ErrorResponse::InternalError(problem),
+        }
+    }
+}
+
+impl From<RedisError> for ErrorResponse {
+    fn from(err: RedisError) -> Self {
+        ApiError::from(err).into()
+    }
+}
+
+impl From<PoolError> for ErrorResponse {
+    fn from(err: PoolError) -> Self {
+        ApiError::from(err).into()
+    }
+}
+
+impl From<RoleError> for ErrorResponse {
+    fn from(err: RoleError) -> Self {
+        ApiError::from(err).into()
+    }
+}
+
+impl From<AutomataError> for ErrorResponse {
+    fn from(err: AutomataError) -> Self {
+        ApiError::from(err).into()
+    }
+}
+
+pub type ApiResult<T> = Result<T, ErrorResponse>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_redis_errors() {
+        let refused: RedisError =
+            std::io::Error::new(std::io::ErrorKind::ConnectionRefused, "refused").into();
+        let err = ApiError::from(refused);
+        assert_eq!(err.kind(), "redis_unavailable");
+        assert!(err.retryable());
+
+        let missing: RedisError = (
+            redis::ErrorKind::ResponseError,
+            "An error was signalled by the server",
+            "Unknown Index name".to_string(),
+        )
+            .into();
+        assert_eq!(ApiError::from(missing).kind(), "index_missing");
+
+        let syntax: RedisError = (
+            redis::ErrorKind::ResponseError,
+            "An error was signalled by the server",
+            "Syntax error at offset 3 near foo".to_string(),
+        )
+            .into();
+        let err = ApiError::from(syntax);
+        assert_eq!(err.kind(), "bad_query");
+        assert!(!err.retryable());
+        assert!(matches!(
+            ErrorResponse::from(err),
+            ErrorResponse::BadRequest(_)
+        ));
+    }
+}
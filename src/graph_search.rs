@@ -1,104 +1,376 @@
-use terraphim_automata::{Dictionary, Matched, load_automata, find_matches};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::collections::HashSet;
+use terraphim_automata::{find_matches, Dictionary};
 
+use redis::Value;
 
-use redis::{FromRedisValue, Value};
+use redis_derive::FromRedisValue;
 
-use redis_derive::{FromRedisValue, ToRedisArgs};
-
-use redis::Commands;
+use itertools::Itertools;
+use redis::AsyncCommands;
 
 mod graph_types;
-use graph_types::{GraphResultSet, GraphResult};
+use graph_types::GraphResultSet;
 
+use crate::automata::Automata;
+use crate::error::ApiError;
+use crate::ingest::Sentence;
+use crate::redis_pool::Connection;
+use terraphim_pipeline::graph::{
+    article_edges_key, article_synonyms_key, concept_synonyms_key, cypher_string,
+    edge_evidence_key, retract_edges_query, sentence_key, Contribution, SynonymCount, LINKS_TO,
+};
 
-use crate::settings::{Settings, self};
+#[derive(Debug, Clone, Deserialize, Serialize, FromRedisValue)]
+pub struct Edge {
+    pub e_id: String,
+    pub t_id: String,
+    pub rank: f64,
+    pub year: Option<i64>,
+    pub e_name: Option<String>,
+    pub t_name: Option<String>,
+    /// Relationship type, e.g. `CO_OCCURS` or `LINKS_TO`
+    pub relation: Option<String>,
+}
 
+/// Ids of the concepts found in `text`
+pub fn matched_concepts(text: &str, automata: &Automata) -> Result<HashSet<String>, ApiError> {
+    let matched_ents = find_matches(text, automata.clone(), false)
+        .map_err(|e| ApiError::Matching(e.to_string()))?;
+    Ok(matched_ents.iter().map(|ent| ent.id.clone()).collect())
+}
 
-#[derive(Debug, Deserialize, Serialize, FromRedisValue)]
-pub struct Edge {
-    e_id: String,
-    t_id: String,
-    rank: f64,
-    year: Option<i64>,
-}
-
-pub fn match_nodes(search_string: &str, automata: HashMap<String, Dictionary>) -> Vec<String> {
-    let matched_ents = find_matches(search_string, automata, false).expect("Failed to find matches");
-    let nodes: HashSet<String> = matched_ents.iter().map(|ent| ent.id.clone()).collect();
-    let nodes: Vec<String> = nodes.into_iter().map(|node|format!("\"{node}\"")).collect();
-    // let nodes: Vec<String> = Vec::new();
-    // for ent in matched_ents.iter() {
-    //     println!("Matched ent {:#?}", ent.id);
-    // }
-    // println!("Matched ents {:#?}", matched_ents);
-    println!("Matched nodes {:#?}", nodes);
-    nodes
-}
-
-pub fn get_edges(
-    settings: &Settings,
+/// Synonyms found in `text` per concept id, in order of appearance
+pub fn matched_synonyms(
+    text: &str,
+    automata: &Automata,
+) -> Result<HashMap<String, Vec<String>>, ApiError> {
+    let matched_ents = find_matches(text, automata.clone(), false)
+        .map_err(|e| ApiError::Matching(e.to_string()))?;
+    let mut synonyms: HashMap<String, Vec<String>> = HashMap::new();
+    for ent in matched_ents {
+        let terms = synonyms.entry(ent.id).or_default();
+        if !terms.contains(&ent.term) {
+            terms.push(ent.term);
+        }
+    }
+    Ok(synonyms)
+}
+
+/// Concept whose thesaurus term is `term`, ignoring case. Looked up
+/// directly, without copying the automata for `find_matches`.
+pub fn concept_by_term<'a>(automata: &'a Automata, term: &str) -> Option<&'a Dictionary> {
+    let term = term.to_lowercase();
+    automata.get(&term).or_else(|| {
+        automata
+            .iter()
+            .find(|(t, _)| t.to_lowercase() == term)
+            .map(|(_, dict)| dict)
+    })
+}
+
+/// Quotes concept ids for use in `edges_query`
+pub fn quoted(ids: &HashSet<String>) -> Vec<String> {
+    ids.iter().map(|node| format!("\"{node}\"")).collect()
+}
+
+/// Restrictions on the edges returned by `edges_query`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct EdgeFilter<'a> {
+    /// Only relationships from these years
+    pub years: Option<&'a [i64]>,
+    /// Only these relationship types, e.g. `["LINKS_TO"]`
+    pub relations: Option<&'a [String]>,
+}
+
+/// Builds the Cypher query returning the strongest edges leaving `nodes`,
+/// optionally restricted by year and relationship type. `LINKS_TO` edges
+/// lead from a note to the concept, so they're also followed backwards.
+/// Edges are returned in their stored direction, as in `edges_scored:*`.
+pub fn edges_query(nodes: &[String], filter: EdgeFilter, limits: i64) -> String {
+    let ids = format!("[{}]", nodes.join(","));
+    let mut params = format!("ids={ids}");
+    let mut conditions = format!(
+        "n.id IN ids AND (id(startNode(r)) = id(n) OR type(r) = {})",
+        cypher_string(LINKS_TO)
+    );
+    if let Some(years) = filter.years {
+        params.push_str(&format!(" years=[{}]", years.iter().join(",")));
+        conditions.push_str(" AND r.year IN $years");
+    }
+    if let Some(relations) = filter.relations {
+        let relations = relations.iter().map(|r| cypher_string(r)).join(",");
+        params.push_str(&format!(" relations=[{relations}]"));
+        conditions.push_str(" AND type(r) IN $relations");
+    }
+    format!("CYPHER {params} limits={limits} WITH $ids as ids MATCH (n:entity)-[r]-(:entity) WHERE {conditions} WITH r, startNode(r) AS e, endNode(r) AS t RETURN e.id, t.id, e.name, t.name, type(r) AS relation, max(r.rank) AS rank, r.year ORDER BY rank DESC LIMIT $limits")
+}
+
+/// Builds the Cypher query returning every relationship from `source` to
+/// `target`, one row per relationship type and year.
+pub fn edges_between_query(source: &str, target: &str) -> String {
+    format!(
+        "CYPHER source={} target={} MATCH (e:entity {{id: $source}})-[r]->(t:entity {{id: $target}}) RETURN e.id, t.id, e.name, t.name, type(r) AS relation, r.rank AS rank, r.year ORDER BY r.year",
+        cypher_string(source),
+        cypher_string(target)
+    )
+}
+
+/// Maps the rows of an edges query into `Edge`, skipping rows without ids.
+pub fn edges_from_result_set(result_set: &GraphResultSet) -> Vec<Edge> {
+    result_set
+        .data
+        .iter()
+        .filter_map(|row| {
+            Some(Edge {
+                e_id: row.get_scalar("e.id")?,
+                t_id: row.get_scalar("t.id")?,
+                rank: row.get_scalar("rank").unwrap_or_default(),
+                year: row.get_scalar("r.year"),
+                e_name: row.get_scalar("e.name"),
+                t_name: row.get_scalar("t.name"),
+                relation: row.get_scalar("relation"),
+            })
+        })
+        .collect()
+}
+
+pub async fn get_edges(
+    con: &mut Connection,
+    graph_name: &str,
     nodes: &[String],
-    years: Option<&[&str]>,
+    filter: EdgeFilter<'_>,
     limits: i64,
-) -> Vec<Edge> {
-    let mut links = Vec::new();
-    // let mut nodes_set = std::collections::HashSet::new();
-    let mut years_set:Vec<String> = Vec::new();
-    let url = settings.redis_url.clone();
-    let client = redis::Client::open(url).unwrap();
-    let mut con = client.get_connection().unwrap();
-    let graph_name = "cord19medical";
-    let ids = format!("[{}]", nodes.join(","));
-    // .replace("\"", "\\\"");
-    
-    // let query = if let Some(years) = years {
-    //     let years: Vec<String> = years.iter().map(String::from).collect();
-    //     format!(
-    //         "WITH {ids} as ids
-    //         MATCH (e:entity)-[r]->(t:entity)
-    //         WHERE e.id IN ids AND r.year IN {years}
-    //         RETURN DISTINCT e.id, t.id, max(r.rank), r.year
-    //         ORDER BY r.rank DESC LIMIT {limits}",
-    //         ids = nodes, years=years, limits = limits
-    //     )
-    // } else {
-        // query withot years
-        // };
-        let query =format!("CYPHER ids={ids} limits={limits} WITH $ids as ids MATCH (e:entity)-[r]->(t:entity) WHERE e.id IN ids RETURN DISTINCT e.id, t.id, max(r.rank), r.year ORDER BY r.rank DESC LIMIT $limits", ids = ids, limits = limits);
-        println!("Query: {}", query);
-        
-        let result_set=redis::cmd("GRAPH.QUERY")
-            .arg(graph_name)
-            .arg(query)
-            // .arg("--compact")
-            .query::<redis::Value>(&mut con)
-            .unwrap();
-    println!("Result set: {:?}", result_set);
-    let result_set: GraphResultSet = GraphResultSet::from_redis_value(&result_set).unwrap();
-    println!("Result set: {:?}", result_set);
-    // let result_vec: Vec<redis::Value> = redis::from_redis_value(&result_set).unwrap();
-    // // let edges= RedisearchResult::from_redis_value(&result_set);
-    // println!("Result set: {:?}", result_vec.len());
-    // println!("Result set 0: {:?}", result_vec[0]);
-    // println!("Result set 1: {:?}", result_vec[1]);
-    // println!("Result set 2: {:?}", result_vec[2]);
-
-    // let my_struct: MyStruct= redis::from_redis_value(&result_vec[0]).unwrap();
-    // println!("Struct: {:?}", my_struct);
-    // // for record in result_set {
-    //     let source_id = record[0].as_str().unwrap().to_owned();
-    //     let target_id = record[1].as_str().unwrap().to_owned();
-    //     let rank = record[2].as_float().unwrap();
-    //     let year = record[3].as_i64();
-    //     links.push(Edge {
-    //         source_id,
-    //         target_id,
-    //         rank,
-    //         year,
-    //     });
-    // }
-    links
-}
\ No newline at end of file
+) -> redis::RedisResult<Vec<Edge>> {
+    let query = edges_query(nodes, filter, limits);
+    tracing::debug!("Query: {}", query);
+
+    let result_set: GraphResultSet = redis::cmd("GRAPH.QUERY")
+        .arg(graph_name)
+        .arg(query)
+        .query_async(con)
+        .await?;
+    tracing::debug!("Result set: {:?}", result_set);
+    Ok(edges_from_result_set(&result_set))
+}
+
+/// Relationships from `source` to `target` in the graph, oldest first
+pub async fn edges_between(
+    con: &mut Connection,
+    graph_name: &str,
+    source: &str,
+    target: &str,
+) -> redis::RedisResult<Vec<Edge>> {
+    let result_set: GraphResultSet = redis::cmd("GRAPH.QUERY")
+        .arg(graph_name)
+        .arg(edges_between_query(source, target))
+        .query_async(con)
+        .await?;
+    Ok(edges_from_result_set(&result_set))
+}
+
+/// Normalised term of the concept `id`, `None` when it's not in the thesaurus
+pub fn concept_name(automata: &Automata, id: &str) -> Option<String> {
+    automata
+        .values()
+        .find(|dict| dict.id == id)
+        .map(|dict| dict.nterm.clone())
+}
+
+/// Graph score of an article and the edges it was found in
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ArticleSupport {
+    pub score: f64,
+    /// Indexes into the edges passed to `article_support`
+    pub edges: Vec<usize>,
+}
+
+impl ArticleSupport {
+    /// The edges the article was found in, out of those passed to `article_support`
+    pub fn supporting(&self, edges: &[Edge]) -> Vec<Edge> {
+        self.edges.iter().map(|i| edges[*i].clone()).collect()
+    }
+}
+
+/// Scores the articles supporting `edges`. Every article found in
+/// `edges_scored:{source}:{target}` scores its occurrence count weighted by
+/// the edge rank, summed over all edges.
+pub async fn article_support(
+    con: &mut Connection,
+    edges: &[Edge],
+) -> redis::RedisResult<HashMap<String, ArticleSupport>> {
+    let mut support: HashMap<String, ArticleSupport> = HashMap::new();
+    for (i, edge) in edges.iter().enumerate() {
+        let scored: Vec<(String, f64)> = con
+            .zrevrange_withscores(format!("edges_scored:{}:{}", edge.e_id, edge.t_id), 0, -1)
+            .await?;
+        for (article_id, score) in scored {
+            let article = support.entry(article_id).or_default();
+            article.score += score * edge.rank;
+            article.edges.push(i);
+        }
+    }
+    Ok(support)
+}
+
+/// Sentences of `article` the edge from `source` to `target` was found in,
+/// highest rank first, at most `limit`. Empty for links and for articles
+/// ingested before sentences were kept.
+pub async fn evidence_sentences(
+    con: &mut Connection,
+    source: &str,
+    target: &str,
+    article: &str,
+    limit: usize,
+) -> Result<Vec<Sentence>, ApiError> {
+    if limit == 0 {
+        return Ok(Vec::new());
+    }
+    let numbers: Vec<usize> = con
+        .zrevrange(
+            edge_evidence_key(source, target, article),
+            0,
+            limit as isize - 1,
+        )
+        .await?;
+    let mut sentences = Vec::new();
+    for n in numbers {
+        let record: Option<String> = con.get(sentence_key(article, n)).await?;
+        if let Some(record) = record {
+            sentences.push(
+                serde_json::from_str(&record).map_err(|e| ApiError::Internal(e.to_string()))?,
+            );
+        }
+    }
+    Ok(sentences)
+}
+
+/// The sentence of `article` with the highest rank for `edge`, see
+/// `evidence_sentences`
+pub async fn evidence(
+    con: &mut Connection,
+    edge: &Edge,
+    article: &str,
+) -> Result<Option<Sentence>, ApiError> {
+    let mut sentences = evidence_sentences(con, &edge.e_id, &edge.t_id, article, 1).await?;
+    Ok(sentences.pop())
+}
+
+/// Removes everything `article` contributed to `edges_scored:*`, the role
+/// graphs, the evidence sentences and the synonym counts, as recorded in
+/// `article_edges:{article}` and `article_synonyms:{article}` when it was
+/// ingested.
+pub async fn retract_article(con: &mut Connection, article: &str) -> redis::RedisResult<()> {
+    let key = article_edges_key(article);
+    let recorded: HashMap<String, f64> = con.hgetall(&key).await?;
+    let mut by_graph: HashMap<(String, String), Vec<Contribution>> = HashMap::new();
+    for (field, rank) in recorded {
+        match Contribution::parse(&field, rank) {
+            Some(contribution) => by_graph
+                .entry((contribution.graph.clone(), contribution.relation.clone()))
+                .or_default()
+                .push(contribution),
+            None => println!("Skipping malformed contribution {} of {}", field, article),
+        }
+    }
+    let mut pipe = redis::pipe();
+    let mut sentences = HashSet::new();
+    for ((graph, relation), contributions) in by_graph.iter() {
+        redis::cmd("GRAPH.QUERY")
+            .arg(graph)
+            .arg(retract_edges_query(relation, contributions))
+            .query_async::<_, Value>(con)
+            .await?;
+        for c in contributions {
+            pipe.zrem(
+                format!("edges_scored:{}:{}", c.source, c.destination),
+                article,
+            )
+            .ignore();
+            let evidence = edge_evidence_key(&c.source, &c.destination, article);
+            let numbers: Vec<usize> = con.zrange(&evidence, 0, -1).await?;
+            sentences.extend(numbers);
+            pipe.del(&evidence).ignore();
+        }
+    }
+    for n in sentences {
+        pipe.del(sentence_key(article, n)).ignore();
+    }
+    pipe.del(&key).ignore();
+
+    let key = article_synonyms_key(article);
+    let recorded: HashMap<String, i64> = con.hgetall(&key).await?;
+    for (field, count) in recorded {
+        match SynonymCount::parse(&field, count) {
+            Some(s) => {
+                pipe.hincr(
+                    concept_synonyms_key(&s.graph, &s.concept),
+                    &s.synonym,
+                    -s.count,
+                )
+                .ignore();
+            }
+            None => println!("Skipping malformed synonym count {} of {}", field, article),
+        }
+    }
+    pipe.del(&key).ignore();
+    pipe.query_async::<_, ()>(con).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::FromRedisValue;
+
+    #[test]
+    fn test_edges_from_recorded_reply() {
+        let reply = std::fs::read("test-data/graph_query_edges.resp").unwrap();
+        let value = redis::parse_redis_value(&reply).unwrap();
+        let result_set = GraphResultSet::from_redis_value(&value).unwrap();
+        let edges = edges_from_result_set(&result_set);
+        assert_eq!(edges.len(), 2);
+        assert_eq!(edges[0].e_id, "01H6VGEFEAVH6ZN4G5TGZZ21RB");
+        assert_eq!(edges[0].t_id, "01H6VGEFE84JW9045V7F5WFXGY");
+        assert_eq!(edges[0].rank, 3.0);
+        assert_eq!(edges[0].year, Some(2023));
+        assert_eq!(edges[1].rank, 1.5);
+        assert_eq!(edges[1].year, None);
+        assert_eq!(edges[0].e_name, None);
+        assert_eq!(edges[0].relation, None);
+    }
+
+    #[test]
+    fn test_edges_query_years() {
+        let nodes = vec!["\"a\"".to_string(), "\"b\"".to_string()];
+        let filter = EdgeFilter {
+            years: Some(&[2022, 2023][..]),
+            ..Default::default()
+        };
+        let query = edges_query(&nodes, filter, 10);
+        assert!(query.starts_with("CYPHER ids=[\"a\",\"b\"] years=[2022,2023] limits=10 "));
+        assert!(query.contains("r.year IN $years"));
+        assert!(!edges_query(&nodes, EdgeFilter::default(), 10).contains("years"));
+
+        let relations = vec!["LINKS_TO".to_string()];
+        let filter = EdgeFilter {
+            relations: Some(&relations),
+            ..Default::default()
+        };
+        let query = edges_query(&nodes, filter, 10);
+        assert!(query.contains(" relations=[\"LINKS_TO\"] "));
+        assert!(query.contains("AND type(r) IN $relations"));
+        // links point at the concept, co-occurrences away from it
+        assert!(query.contains("MATCH (n:entity)-[r]-(:entity)"));
+        assert!(query.contains("(id(startNode(r)) = id(n) OR type(r) = \"LINKS_TO\")"));
+        assert!(query.contains("WITH r, startNode(r) AS e, endNode(r) AS t RETURN e.id, t.id"));
+    }
+
+    #[test]
+    fn test_edges_between_query() {
+        let query = edges_between_query("a", "b\"c");
+        assert!(query.starts_with("CYPHER source=\"a\" target=\"b\\\"c\" MATCH "));
+        assert!(query.contains("RETURN e.id, t.id, e.name, t.name, type(r) AS relation"));
+    }
+}
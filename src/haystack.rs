@@ -0,0 +1,278 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use terraphim_automata::find_matches;
+use terraphim_markdown_parser::{expand_home, files_with_extensions, parse_note, slug};
+
+use crate::automata::Automata;
+use crate::error::ApiError;
+use crate::graph_search::matched_concepts;
+use crate::redisearch::RedisearchResult;
+use crate::roles::{Role, RoleError};
+
+/// Name of the local filesystem plugin in the role config
+pub const GREP_PLUGIN: &str = "terraphim-grep";
+
+/// Title matches count this much more than body matches, like the title
+/// weight of the article index
+const TITLE_WEIGHT: f64 = 5.0;
+
+/// A search of one haystack
+pub struct HaystackQuery<'a> {
+    pub search_term: &'a str,
+    /// Role automata, when the role has one
+    pub automata: Option<&'a Automata>,
+    /// Concepts of the search term, empty without automata
+    pub concepts: &'a HashSet<String>,
+}
+
+/// A document source searched outside of Redis, configured per role as a plugin
+pub trait Haystack: Send + Sync {
+    /// Matching documents with their score set
+    fn search(&self, query: &HaystackQuery) -> Result<Vec<RedisearchResult>, ApiError>;
+}
+
+/// Directories of `dirs` which aren't inside another one of them, so
+/// nested haystacks aren't read twice
+pub fn outermost_dirs(dirs: &[PathBuf]) -> Vec<PathBuf> {
+    let mut outermost: Vec<PathBuf> = Vec::new();
+    for dir in dirs {
+        if !outermost.contains(dir) && !dirs.iter().any(|o| o != dir && dir.starts_with(o)) {
+            outermost.push(dir.clone());
+        }
+    }
+    outermost
+}
+
+/// The haystack plugins of the role
+pub fn haystacks(role: &Role) -> Result<Vec<Box<dyn Haystack>>, RoleError> {
+    let mut grep_dirs = Vec::new();
+    for plugin in role.plugins.iter() {
+        match (plugin.name.as_str(), plugin.haystack.as_deref()) {
+            (GREP_PLUGIN, Some(dir)) if !dir.is_empty() => {
+                let dir = expand_home(dir);
+                grep_dirs.push(dir.canonicalize().unwrap_or(dir));
+            }
+            (name, _) => {
+                return Err(RoleError::UnknownPlugin {
+                    role: role.shortname.clone(),
+                    name: name.to_string(),
+                })
+            }
+        }
+    }
+    Ok(outermost_dirs(&grep_dirs)
+        .into_iter()
+        .map(|dir| Box::new(GrepHaystack::new(dir)) as Box<dyn Haystack>)
+        .collect())
+}
+
+/// Searches every haystack on the blocking pool. Documents found in more
+/// than one haystack, e.g. nested directories, are returned once.
+/// Best score first.
+pub async fn search_haystacks(
+    haystacks: Vec<Box<dyn Haystack>>,
+    search_term: String,
+    automata: Option<Arc<Automata>>,
+) -> Result<Vec<RedisearchResult>, ApiError> {
+    tokio::task::spawn_blocking(move || {
+        let automata = automata.as_deref();
+        let concepts = match automata {
+            Some(automata) => matched_concepts(&search_term, automata)?,
+            None => HashSet::new(),
+        };
+        let query = HaystackQuery {
+            search_term: &search_term,
+            automata,
+            concepts: &concepts,
+        };
+        let mut seen = HashSet::new();
+        let mut results = Vec::new();
+        for haystack in haystacks.iter() {
+            for result in haystack.search(&query)? {
+                if seen.insert(result.url.clone()) {
+                    results.push(result);
+                }
+            }
+        }
+        results.sort_by(|a, b| {
+            b.score
+                .unwrap_or_default()
+                .total_cmp(&a.score.unwrap_or_default())
+                .then_with(|| a.id.cmp(&b.id))
+        });
+        Ok(results)
+    })
+    .await
+    .map_err(|e| ApiError::Internal(e.to_string()))?
+}
+
+/// `terraphim-grep`: scans the Markdown and text files of a directory. With
+/// an automata a file scores the occurrences of the search term's concepts,
+/// otherwise the occurrences of its words.
+pub struct GrepHaystack {
+    root: PathBuf,
+}
+
+impl GrepHaystack {
+    pub fn new(root: PathBuf) -> Self {
+        GrepHaystack { root }
+    }
+
+    fn document(&self, path: &Path) -> Result<RedisearchResult, String> {
+        let input = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        let url = format!("file://{}", path.display());
+        if path.extension().is_some_and(|e| e == "md") {
+            let note = parse_note(&input, relative).map_err(|e| e.to_string())?;
+            return Ok(RedisearchResult {
+                id: note.id,
+                title: note.title,
+                url,
+                body: note.body,
+                description: note.description,
+                tags: Some(note.tags).filter(|tags| !tags.is_empty()),
+                ..Default::default()
+            });
+        }
+        Ok(RedisearchResult {
+            id: slug(&relative.with_extension("").to_string_lossy()),
+            title: relative
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            url,
+            body: input,
+            ..Default::default()
+        })
+    }
+}
+
+fn word_count(text: &str, words: &[String]) -> usize {
+    let text = text.to_lowercase();
+    words.iter().map(|w| text.matches(w.as_str()).count()).sum()
+}
+
+/// Occurrences of the query's concepts in the title and in the body. Both
+/// are matched at once, every match copies the automata.
+fn concept_counts(
+    title: &str,
+    body: &str,
+    query: &HaystackQuery,
+) -> Result<(usize, usize), ApiError> {
+    let automata = match query.automata {
+        Some(automata) => automata,
+        None => return Ok((0, 0)),
+    };
+    let text = format!("{title}\n{body}");
+    let matched = find_matches(&text, automata.clone(), true)
+        .map_err(|e| ApiError::Matching(e.to_string()))?;
+    let mut counts = (0, 0);
+    for m in matched.iter().filter(|m| query.concepts.contains(&m.id)) {
+        match m.pos {
+            Some((start, _)) if start < title.len() => counts.0 += 1,
+            _ => counts.1 += 1,
+        }
+    }
+    Ok(counts)
+}
+
+/// Score of a document: title and body matches, title weighted higher
+fn score(document: &RedisearchResult, query: &HaystackQuery) -> Result<f64, ApiError> {
+    let (title, body) = if query.concepts.is_empty() {
+        let words: Vec<String> = query
+            .search_term
+            .split_whitespace()
+            .map(str::to_lowercase)
+            .collect();
+        (
+            word_count(&document.title, &words),
+            word_count(&document.body, &words),
+        )
+    } else {
+        concept_counts(&document.title, &document.body, query)?
+    };
+    Ok(title as f64 * TITLE_WEIGHT + body as f64)
+}
+
+impl Haystack for GrepHaystack {
+    fn search(&self, query: &HaystackQuery) -> Result<Vec<RedisearchResult>, ApiError> {
+        // a missing directory is an empty haystack, not a failed search
+        let files = match files_with_extensions(&self.root, &["md", "txt"]) {
+            Ok(files) => files,
+            Err(e) => {
+                tracing::warn!("Skipping haystack {}: {}", self.root.display(), e);
+                return Ok(Vec::new());
+            }
+        };
+        let mut results = Vec::new();
+        for path in files {
+            let mut document = match self.document(&path) {
+                Ok(document) => document,
+                Err(e) => {
+                    tracing::warn!("Skipping {}: {}", path.display(), e);
+                    continue;
+                }
+            };
+            let score = score(&document, query)?;
+            if score > 0.0 {
+                document.score = Some(score);
+                results.push(document);
+            }
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outermost_dirs() {
+        let dirs: Vec<PathBuf> = ["/notes/World", "/notes", "/other", "/notes"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        assert_eq!(
+            outermost_dirs(&dirs),
+            vec![PathBuf::from("/notes"), PathBuf::from("/other")]
+        );
+    }
+
+    #[test]
+    fn test_grep_haystack_without_automata() {
+        let root = std::env::temp_dir().join(format!("terraphim-grep-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("World")).unwrap();
+        std::fs::create_dir_all(root.join(".obsidian")).unwrap();
+        std::fs::write(
+            root.join("World/Rust.md"),
+            "---\ntags: [lang]\n---\n# Rust\n\nRust has a borrow checker, see [[Ownership]].",
+        )
+        .unwrap();
+        std::fs::write(root.join("notes.txt"), "the borrow checker").unwrap();
+        std::fs::write(root.join(".obsidian/rust.md"), "rust rust rust").unwrap();
+
+        let haystack = GrepHaystack::new(root.clone());
+        let concepts = HashSet::new();
+        let query = HaystackQuery {
+            search_term: "Rust",
+            automata: None,
+            concepts: &concepts,
+        };
+        let results = haystack.search(&query).unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].id, "world-rust");
+        assert_eq!(results[0].tags, Some(vec!["lang".to_string()]));
+        // title once, body twice (heading and paragraph)
+        assert_eq!(results[0].score, Some(TITLE_WEIGHT + 2.0));
+
+        let query = HaystackQuery {
+            search_term: "borrow",
+            ..query
+        };
+        assert_eq!(haystack.search(&query).unwrap().len(), 2);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
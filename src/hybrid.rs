@@ -0,0 +1,169 @@
+use std::collections::HashMap;
+
+use crate::roles::{Role, RoleError};
+
+/// How `/hsearch/` combines the full-text and graph rankings, taken from the
+/// role's `fusion`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FusionMethod {
+    /// Reciprocal rank fusion, `sum(1 / (k + rank))` over both rankings
+    ReciprocalRank { k: f64 },
+    /// Scores min-max normalised per ranking, text weighted by `text_weight`
+    /// and graph by `1 - text_weight`
+    WeightedSum { text_weight: f64 },
+}
+
+impl Default for FusionMethod {
+    fn default() -> Self {
+        FusionMethod::ReciprocalRank { k: 60.0 }
+    }
+}
+
+impl FusionMethod {
+    /// Parses `rrf`, `rrf:{k}`, `weighted_sum` or `weighted_sum:{text_weight}`,
+    /// `None` for any other name or a parameter which is not a number.
+    pub fn parse(name: &str) -> Option<Self> {
+        let (name, param) = match name.split_once(':') {
+            Some((name, param)) => (name, Some(param.trim().parse::<f64>().ok()?)),
+            None => (name, None),
+        };
+        match name.trim() {
+            "weighted_sum" | "weighted" => Some(FusionMethod::WeightedSum {
+                text_weight: param.unwrap_or(0.5).clamp(0.0, 1.0),
+            }),
+            "rrf" | "reciprocal_rank" => Some(FusionMethod::ReciprocalRank {
+                k: param.filter(|k| *k >= 0.0).unwrap_or(60.0),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Fusion method of the role, reciprocal rank fusion when it sets none.
+/// Checked for every role at startup, like `relevance_function`.
+pub fn fusion_method(role: &Role) -> Result<FusionMethod, RoleError> {
+    match role.fusion.as_deref() {
+        None => Ok(FusionMethod::default()),
+        Some(name) => FusionMethod::parse(name).ok_or_else(|| RoleError::UnknownFusion {
+            role: role.shortname.clone(),
+            name: name.to_string(),
+        }),
+    }
+}
+
+/// An article in the fused ranking with its place in each input ranking
+#[derive(Debug, Clone, PartialEq)]
+pub struct Fused {
+    pub id: String,
+    pub score: f64,
+    pub text_score: Option<f64>,
+    pub graph_score: Option<f64>,
+}
+
+fn normalised(ranking: &[(String, f64)]) -> HashMap<&str, f64> {
+    let min = ranking.iter().map(|r| r.1).fold(f64::INFINITY, f64::min);
+    let max = ranking
+        .iter()
+        .map(|r| r.1)
+        .fold(f64::NEG_INFINITY, f64::max);
+    ranking
+        .iter()
+        .map(|(id, score)| {
+            let score = if max > min {
+                (score - min) / (max - min)
+            } else {
+                1.0
+            };
+            (id.as_str(), score)
+        })
+        .collect()
+}
+
+/// Fuses two rankings, each ordered best first, into one ordered best first.
+pub fn fuse(text: &[(String, f64)], graph: &[(String, f64)], method: &FusionMethod) -> Vec<Fused> {
+    let mut fused: HashMap<&str, Fused> = HashMap::new();
+    for (id, score) in text {
+        fused
+            .entry(id.as_str())
+            .or_insert_with(|| Fused::new(id))
+            .text_score = Some(*score);
+    }
+    for (id, score) in graph {
+        fused
+            .entry(id.as_str())
+            .or_insert_with(|| Fused::new(id))
+            .graph_score = Some(*score);
+    }
+    match method {
+        FusionMethod::ReciprocalRank { k } => {
+            for ranking in [text, graph] {
+                for (rank, (id, _)) in ranking.iter().enumerate() {
+                    if let Some(f) = fused.get_mut(id.as_str()) {
+                        f.score += 1.0 / (k + rank as f64 + 1.0);
+                    }
+                }
+            }
+        }
+        FusionMethod::WeightedSum { text_weight } => {
+            for (ranking, weight) in [(text, *text_weight), (graph, 1.0 - text_weight)] {
+                for (id, score) in normalised(ranking) {
+                    if let Some(f) = fused.get_mut(id) {
+                        f.score += weight * score;
+                    }
+                }
+            }
+        }
+    }
+    let mut fused: Vec<Fused> = fused.into_values().collect();
+    fused.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.id.cmp(&b.id)));
+    fused
+}
+
+impl Fused {
+    fn new(id: &str) -> Self {
+        Fused {
+            id: id.to_string(),
+            score: 0.0,
+            text_score: None,
+            graph_score: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranking(items: &[(&str, f64)]) -> Vec<(String, f64)> {
+        items.iter().map(|(id, s)| (id.to_string(), *s)).collect()
+    }
+
+    #[test]
+    fn test_fuse_reciprocal_rank() {
+        let text = ranking(&[("a", 9.0), ("b", 5.0)]);
+        let graph = ranking(&[("b", 30.0), ("c", 1.0)]);
+        let fused = fuse(&text, &graph, &FusionMethod::ReciprocalRank { k: 0.0 });
+        let ids: Vec<&str> = fused.iter().map(|f| f.id.as_str()).collect();
+        // b: 1/2 + 1/1, a: 1/1, c: 1/2
+        assert_eq!(ids, vec!["b", "a", "c"]);
+        assert_eq!(fused[0].score, 1.5);
+        assert_eq!(fused[0].text_score, Some(5.0));
+        assert_eq!(fused[0].graph_score, Some(30.0));
+        assert_eq!(fused[2].text_score, None);
+    }
+
+    #[test]
+    fn test_fuse_weighted_sum() {
+        let text = ranking(&[("a", 4.0), ("b", 2.0)]);
+        let graph = ranking(&[("b", 10.0), ("a", 0.0)]);
+        let method = FusionMethod::parse("weighted_sum:0.75").unwrap();
+        assert_eq!(method, FusionMethod::WeightedSum { text_weight: 0.75 });
+        let fused = fuse(&text, &graph, &method);
+        assert_eq!(fused[0].id, "a");
+        assert_eq!(fused[0].score, 0.75);
+        assert_eq!(fused[1].score, 0.25);
+        assert_eq!(FusionMethod::parse("rust"), None);
+        assert_eq!(FusionMethod::parse("rrf:sixty"), None);
+        assert_eq!(FusionMethod::parse("rrf"), Some(FusionMethod::default()));
+    }
+}
@@ -0,0 +1,833 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chrono::Datelike;
+use itertools::Itertools;
+use poem_openapi::Object;
+use redis::Value;
+use serde::{Deserialize, Serialize};
+use terraphim_automata::{find_matches, Matched};
+use terraphim_markdown_parser::{expand_home, parse_dir_in, Note};
+use terraphim_pipeline::graph::{
+    article_edges_key, article_synonyms_key, by_relation, concept_synonyms_key, edge_evidence_key,
+    merge_edges_query, sentence_key, Contribution, EdgeRecord, SynonymCount, CO_OCCURS,
+    EDGES_VERSION, LINKS_TO,
+};
+use terraphim_pipeline::shard::Sharding;
+use terraphim_pipeline::split_paragraph_offsets;
+use ulid::Ulid;
+
+use crate::automata::Automata;
+use crate::error::ApiError;
+use crate::graph_search::{concept_by_term, retract_article};
+use crate::haystack::outermost_dirs;
+use crate::redis_pool::Connection;
+use crate::redisearch::{parse_redisearch_response, ReplyLayout};
+use crate::roles::{Role, RoleRegistry};
+use crate::search_index::INDEX_ALIAS;
+use crate::search_query::escape;
+use crate::Article;
+
+/// Articles stored and extracted per round of a bulk import
+pub const BULK_BATCH_SIZE: usize = 100;
+
+/// Words between two concepts which halve the rank of their edge
+const RANK_HALF_DISTANCE: f64 = 5.0;
+
+/// How the edges of stored articles are written, shared with the handlers
+/// through poem `Data`
+#[derive(Debug, Clone)]
+pub struct EdgeWrites {
+    /// Which `edges_matched_*` shard an edge goes to
+    pub sharding: Sharding,
+    /// Merge edges into the graph while handling the request, see `Settings`
+    pub inline_graph_writes: bool,
+}
+
+/// What storing articles of one role needs
+pub struct IngestContext<'a> {
+    pub con: &'a mut Connection,
+    pub role: &'a Role,
+    pub automata: Arc<Automata>,
+    pub writes: &'a EdgeWrites,
+}
+
+/// Year of the edges of an article: the year it was published, otherwise
+/// the year it was stored (`created`, in seconds).
+pub fn edge_year(published: Option<&str>, created: u64) -> i64 {
+    let published = published.map(str::trim).and_then(|p| {
+        let digits = p.find(|c: char| !c.is_ascii_digit()).unwrap_or(p.len());
+        // a year, not a timestamp
+        if digits == 4 {
+            p[..4].parse().ok()
+        } else {
+            None
+        }
+    });
+    published.unwrap_or_else(|| {
+        chrono::DateTime::from_timestamp(created as i64, 0)
+            .map(|time| i64::from(time.year()))
+            .unwrap_or_default()
+    })
+}
+
+/// Terms of several words are more specific than single words
+fn term_weight(term: &str) -> f64 {
+    term.split_whitespace().count().max(1) as f64
+}
+
+/// Rank of one co-occurrence: the weights of both terms, halved for every
+/// `RANK_HALF_DISTANCE` words between them. Two adjacent one-word terms rank 1.
+pub fn edge_rank(source_term: &str, destination_term: &str, words_between: usize) -> f64 {
+    term_weight(source_term) * term_weight(destination_term)
+        / (1.0 + words_between as f64 / RANK_HALF_DISTANCE)
+}
+
+/// Words of `text` between two matches, 0 when they touch or overlap
+fn words_between(text: &str, a: Option<(usize, usize)>, b: Option<(usize, usize)>) -> usize {
+    let (a, b) = match (a, b) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return 0,
+    };
+    let (start, end) = if a.0 <= b.0 { (a.1, b.0) } else { (b.1, a.0) };
+    text.get(start..end)
+        .map(|between| between.split_whitespace().count())
+        .unwrap_or_default()
+}
+
+/// Sentence of an article which co-occurring concepts were found in,
+/// stored at `article:{id}:sentence:{n}` as evidence of their edges
+#[derive(Debug, Clone, PartialEq, Object, Serialize, Deserialize)]
+pub struct Sentence {
+    /// Position of the sentence in the article body, from 0
+    pub n: usize,
+    pub text: String,
+    /// Byte offset of the sentence in the article body
+    pub start: usize,
+    /// Byte offset after the sentence
+    pub end: usize,
+}
+
+/// Concepts found in an article
+#[derive(Debug, Default)]
+pub struct Extraction {
+    /// Pairs of concepts co-occurring in a sentence, in extraction order
+    pub edges: Vec<EdgeRecord>,
+    /// Occurrences per concept id and synonym
+    pub synonyms: BTreeMap<(String, String), i64>,
+    /// Sentences the edges were found in
+    pub sentences: Vec<Sentence>,
+}
+
+/// Matches of `body` grouped by the sentence they lie in. Matches spanning
+/// two sentences or without a position are left out.
+fn by_sentence(sentences: &[(usize, &str)], matched: Vec<Matched>) -> Vec<Vec<Matched>> {
+    let mut grouped = vec![Vec::new(); sentences.len()];
+    for ent in matched {
+        let (from, to) = match ent.pos {
+            Some(pos) => pos,
+            None => continue,
+        };
+        // sentences are ordered by offset
+        let i = sentences.partition_point(|(start, _)| *start <= from);
+        if i == 0 {
+            continue;
+        }
+        let (start, sentence) = sentences[i - 1];
+        if to <= start + sentence.len() {
+            grouped[i - 1].push(ent);
+        }
+    }
+    grouped
+}
+
+/// Matches the concepts of every sentence of `body`. Concepts are named by
+/// their normalised term, so synonyms of one concept in a sentence don't
+/// make an edge to itself. Edges get `year`, a rank from `edge_rank` and the
+/// number of their sentence, kept in `sentences`. The body is matched at
+/// once, matching sentence by sentence copies the automata every time.
+pub fn extract_edges(body: &str, automata: &Automata, year: i64) -> Result<Extraction, ApiError> {
+    let mut extraction = Extraction::default();
+    let sentences = split_paragraph_offsets(body);
+    let matched = find_matches(body, automata.clone(), true)
+        .map_err(|e| ApiError::Matching(e.to_string()))?;
+    let grouped = by_sentence(&sentences, matched);
+    for (n, ((start, sentence), matched_ents)) in sentences.into_iter().zip(grouped).enumerate() {
+        for ent in matched_ents.iter() {
+            *extraction
+                .synonyms
+                .entry((ent.id.clone(), ent.term.clone()))
+                .or_default() += 1;
+        }
+        let found = extraction.edges.len();
+        for pair in matched_ents.into_iter().combinations(2) {
+            if pair[0].id == pair[1].id {
+                continue;
+            }
+            extraction.edges.push(EdgeRecord {
+                source: pair[0].id.clone(),
+                destination: pair[1].id.clone(),
+                source_name: pair[0].nterm.clone(),
+                destination_name: pair[1].nterm.clone(),
+                source_synonym: Some(pair[0].term.clone()),
+                destination_synonym: Some(pair[1].term.clone()),
+                rank: edge_rank(
+                    &pair[0].term,
+                    &pair[1].term,
+                    words_between(body, pair[0].pos, pair[1].pos),
+                ),
+                year,
+                relation: CO_OCCURS.to_string(),
+                sentence: Some(n),
+            });
+        }
+        if extraction.edges.len() > found {
+            extraction.sentences.push(Sentence {
+                n,
+                text: sentence.to_string(),
+                start,
+                end: start + sentence.len(),
+            });
+        }
+    }
+    Ok(extraction)
+}
+
+/// Id of the article titled `title`, ignoring case
+async fn article_by_title(con: &mut Connection, title: &str) -> Result<Option<String>, ApiError> {
+    let words: Vec<String> = title.split_whitespace().map(escape).collect();
+    if words.is_empty() {
+        return Ok(None);
+    }
+    let values: Value = redis::cmd("FT.SEARCH")
+        .arg(INDEX_ALIAS)
+        .arg(format!("@title:({})", words.join(" ")))
+        .arg("RETURN")
+        .arg(1)
+        .arg("title")
+        .arg("LIMIT")
+        .arg(0)
+        .arg(10)
+        .query_async(con)
+        .await?;
+    let (_, results) = parse_redisearch_response(&values, &ReplyLayout::default())?;
+    Ok(results
+        .into_iter()
+        .find(|r| r.title.to_lowercase() == title.to_lowercase())
+        .map(|r| r.id))
+}
+
+/// Resolves the `[[wikilinks]]` of article `id` to `LINKS_TO` edges: to the
+/// concept when the whole target is a term of the role automata, otherwise
+/// to the article with that title. Unresolved links are skipped.
+pub async fn link_edges(
+    id: &str,
+    title: &str,
+    links: &[String],
+    automata: &Automata,
+    year: i64,
+    con: &mut Connection,
+) -> Result<Vec<EdgeRecord>, ApiError> {
+    let mut edges = Vec::new();
+    // collected up front, the iterator's closures can't be held across
+    // `.await` in a handler future that has to be Send
+    let targets: Vec<String> = links
+        .iter()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty())
+        .unique()
+        .map(str::to_string)
+        .collect();
+    for target in targets.iter() {
+        let destination = match concept_by_term(automata, target) {
+            Some(concept) => Some((concept.id.clone(), concept.nterm.clone())),
+            None => article_by_title(con, target)
+                .await?
+                .filter(|article| article != id)
+                .map(|article| (article, target.clone())),
+        };
+        match destination {
+            Some((destination, destination_name)) => edges.push(EdgeRecord {
+                source: id.to_string(),
+                destination,
+                source_name: title.to_string(),
+                destination_name,
+                source_synonym: None,
+                destination_synonym: None,
+                rank: 1.0,
+                year,
+                relation: LINKS_TO.to_string(),
+                sentence: None,
+            }),
+            None => println!("Unresolved link [[{}]] in {}", target, id),
+        }
+    }
+    Ok(edges)
+}
+
+/// Publishes the edges of article `id` to the role's `edges_matched_*`
+/// stream shards, stores the sentences they were found in and counts the
+/// synonyms found. With `inline_graph_writes` the edges are also scored and
+/// merged into the graph here, otherwise `edges_worker` does it. The edges
+/// carry a new `EDGES_VERSION` of the article, so the worker can skip them
+/// once the article is updated or deleted.
+pub async fn write_edges(
+    ctx: &mut IngestContext<'_>,
+    id: &str,
+    extraction: &Extraction,
+) -> Result<(), ApiError> {
+    let role = ctx.role;
+    let writes = ctx.writes;
+    let sharding = &writes.sharding;
+    let inline_graph_writes = writes.inline_graph_writes;
+    let con = &mut *ctx.con;
+    let edges = &extraction.edges;
+    let version = Ulid::new().to_string();
+    let mut pipe = redis::pipe();
+    pipe.hset(format!("article:{}", id), EDGES_VERSION, &version)
+        .ignore();
+    for ((concept, synonym), count) in extraction.synonyms.iter() {
+        let mention = SynonymCount {
+            graph: role.graph_name.clone(),
+            concept: concept.clone(),
+            synonym: synonym.clone(),
+            count: *count,
+        };
+        pipe.hincr(
+            concept_synonyms_key(&role.graph_name, concept),
+            synonym,
+            *count,
+        )
+        .ignore();
+        pipe.hincr(article_synonyms_key(id), mention.field(), *count)
+            .ignore();
+    }
+    for sentence in extraction.sentences.iter() {
+        let record =
+            serde_json::to_string(sentence).map_err(|e| ApiError::Internal(e.to_string()))?;
+        pipe.set(sentence_key(id, sentence.n), record).ignore();
+    }
+    for edge in edges {
+        let mut xadd = redis::cmd("XADD");
+        xadd.arg(sharding.stream(&role.shortname, id, edge))
+            .arg("*")
+            .arg("article")
+            .arg(id)
+            .arg("graph")
+            .arg(&role.graph_name)
+            .arg("source")
+            .arg(&edge.source)
+            .arg("destination")
+            .arg(&edge.destination)
+            .arg("source_name")
+            .arg(&edge.source_name)
+            .arg("destination_name")
+            .arg(&edge.destination_name)
+            .arg("rank")
+            .arg(edge.rank)
+            .arg("year")
+            .arg(edge.year)
+            .arg("relation")
+            .arg(&edge.relation)
+            .arg("version")
+            .arg(&version);
+        if let Some(synonym) = &edge.source_synonym {
+            xadd.arg("source_synonym").arg(synonym);
+        }
+        if let Some(synonym) = &edge.destination_synonym {
+            xadd.arg("destination_synonym").arg(synonym);
+        }
+        if let Some(n) = edge.sentence {
+            xadd.arg("sentence").arg(n);
+        }
+        pipe.add_command(xadd).ignore();
+        if inline_graph_writes {
+            pipe.zincr(
+                format!("edges_scored:{}:{}", edge.source, edge.destination),
+                id,
+                edge.rank,
+            )
+            .ignore();
+            if let Some(n) = edge.sentence {
+                pipe.zincr(
+                    edge_evidence_key(&edge.source, &edge.destination, id),
+                    n,
+                    edge.rank,
+                )
+                .ignore();
+            }
+        }
+    }
+    pipe.query_async::<_, ()>(con).await?;
+    if !inline_graph_writes || edges.is_empty() {
+        return Ok(());
+    }
+    for (relation, edges) in by_relation(edges) {
+        redis::cmd("GRAPH.QUERY")
+            .arg(&role.graph_name)
+            .arg(merge_edges_query(relation, &edges))
+            .query_async::<_, redis::Value>(con)
+            .await?;
+    }
+    let mut pipe = redis::pipe();
+    for edge in edges {
+        pipe.cmd("HINCRBYFLOAT")
+            .arg(article_edges_key(id))
+            .arg(Contribution::new(&role.graph_name, edge).field())
+            .arg(edge.rank)
+            .ignore();
+    }
+    pipe.query_async::<_, ()>(con).await?;
+    Ok(())
+}
+
+/// Splits the article into sentences, matches the role's concepts in each
+/// and writes every co-occurring pair together with the article's
+/// `[[wikilinks]]`, see `write_edges`. `created` is the stored creation
+/// time, the year of the edges unless the article was published.
+pub async fn parse_article(
+    ctx: &mut IngestContext<'_>,
+    article: &Article,
+    id: &str,
+    created: u64,
+) -> Result<(), ApiError> {
+    let year = edge_year(article.published.as_deref(), created);
+    let mut extraction = extract_edges(&article.body, &ctx.automata, year)?;
+    let links = article.links.as_deref().unwrap_or_default();
+    extraction
+        .edges
+        .extend(link_edges(id, &article.title, links, &ctx.automata, year, ctx.con).await?);
+    tracing::debug!("Edges of article {}: {}", id, extraction.edges.len());
+    write_edges(ctx, id, &extraction).await
+}
+
+/// Outcome of one article of a bulk import
+#[derive(Debug, Object)]
+pub struct BulkItem {
+    /// Position of the article in the request body
+    pub index: usize,
+    /// Id the article is stored under, missing when it couldn't be read
+    pub id: Option<String>,
+    /// True when an article with the same id was replaced
+    pub replaced: bool,
+    pub error: Option<String>,
+}
+
+/// Outcome of a bulk import
+#[derive(Debug, Object, Default)]
+pub struct BulkReport {
+    pub stored: usize,
+    pub failed: usize,
+    pub items: Vec<BulkItem>,
+}
+
+impl BulkReport {
+    fn push(&mut self, item: BulkItem) {
+        match item.error {
+            Some(_) => self.failed += 1,
+            None => self.stored += 1,
+        }
+        self.items.push(item);
+    }
+}
+
+/// Creation time of an article as stored in the `created` field. Supplied
+/// ULIDs carry it, other ids get the current time.
+pub fn created_from_id(id: &str) -> u64 {
+    match Ulid::from_string(id) {
+        Ok(ulid) => ulid.timestamp_ms() / 1000,
+        Err(_) => Ulid::new().timestamp_ms() / 1000,
+    }
+}
+
+/// Drops every article followed by another with the same id, reporting it,
+/// so a batch never extracts the same article twice
+fn last_per_id(
+    articles: Vec<(usize, String, Article)>,
+    report: &mut BulkReport,
+) -> Vec<(usize, String, Article)> {
+    let last: HashMap<String, usize> = articles
+        .iter()
+        .map(|(index, id, _)| (id.clone(), *index))
+        .collect();
+    let mut kept = Vec::new();
+    for (index, id, article) in articles {
+        match last.get(&id) {
+            Some(later) if *later != index => report.push(BulkItem {
+                index,
+                error: Some(format!("replaced by article {} with the same id", later)),
+                id: Some(id),
+                replaced: false,
+            }),
+            _ => kept.push((index, id, article)),
+        }
+    }
+    kept
+}
+
+/// Stores and extracts one batch of a bulk import. Articles with their own
+/// `id` replace the stored article of that id, retracting its old edges, so
+/// importing the same file twice leaves the same state. Of several articles
+/// with the same id in a batch only the last is stored. Extraction runs on
+/// the blocking pool in parallel, the hashes are written in one pipeline.
+pub async fn ingest_batch(
+    ctx: &mut IngestContext<'_>,
+    batch: Vec<(usize, Result<Article, String>)>,
+    report: &mut BulkReport,
+) -> Result<(), ApiError> {
+    let mut articles = Vec::new();
+    for (index, article) in batch {
+        match article {
+            Ok(article) => {
+                let id = article
+                    .id
+                    .clone()
+                    .filter(|id| !id.is_empty())
+                    .unwrap_or_else(|| Ulid::new().to_string());
+                articles.push((index, id, article));
+            }
+            Err(error) => report.push(BulkItem {
+                index,
+                id: None,
+                replaced: false,
+                error: Some(error),
+            }),
+        }
+    }
+    let articles = last_per_id(articles, report);
+    if articles.is_empty() {
+        return Ok(());
+    }
+    let mut exists = redis::pipe();
+    let mut created = redis::pipe();
+    for (_, id, _) in articles.iter() {
+        exists.exists(format!("article:{}", id));
+        created.hget(format!("article:{}", id), "created");
+    }
+    let exists: Vec<bool> = exists.query_async(ctx.con).await?;
+    let created: Vec<Option<u64>> = created.query_async(ctx.con).await?;
+    // a replaced article keeps its creation time
+    let created: Vec<u64> = articles
+        .iter()
+        .zip(created)
+        .map(|((_, id, _), created)| created.unwrap_or_else(|| created_from_id(id)))
+        .collect();
+
+    let extractions = articles
+        .iter()
+        .zip(created.iter())
+        .map(|((_, _, article), created)| {
+            let body = article.body.clone();
+            let year = edge_year(article.published.as_deref(), *created);
+            let automata = ctx.automata.clone();
+            tokio::task::spawn_blocking(move || extract_edges(&body, &automata, year))
+        });
+    let extracted = futures_util::future::join_all(extractions).await;
+
+    let mut pending = Vec::new();
+    for ((((index, id, article), extraction), replaced), created) in
+        articles.into_iter().zip(extracted).zip(exists).zip(created)
+    {
+        let extraction = match extraction {
+            Ok(Ok(extraction)) => Ok(extraction),
+            Ok(Err(e)) => Err(e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        match extraction {
+            Ok(extraction) => pending.push((index, id, article, extraction, replaced, created)),
+            Err(error) => report.push(BulkItem {
+                index,
+                id: Some(id),
+                replaced: false,
+                error: Some(error),
+            }),
+        }
+    }
+
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    let mut stored = Vec::new();
+    for (index, id, article, extraction, replaced, created) in pending {
+        let key = format!("article:{}", id);
+        if replaced {
+            if let Err(e) = retract_article(ctx.con, &id).await {
+                report.push(BulkItem {
+                    index,
+                    id: Some(id),
+                    replaced,
+                    error: Some(ApiError::from(e).to_string()),
+                });
+                continue;
+            }
+            pipe.del(&key).ignore();
+        }
+        pipe.cmd("HSET")
+            .arg(&key)
+            .arg(&article)
+            .arg("created")
+            .arg(created)
+            .ignore();
+        let year = edge_year(article.published.as_deref(), created);
+        stored.push((index, id, replaced, article, extraction, year));
+    }
+    pipe.query_async::<_, ()>(ctx.con).await?;
+
+    // links are resolved once the whole batch is stored, so notes of the
+    // batch can link to each other
+    for (index, id, replaced, article, mut extraction, year) in stored {
+        let links = article.links.as_deref().unwrap_or_default();
+        let written =
+            match link_edges(&id, &article.title, links, &ctx.automata, year, ctx.con).await {
+                Ok(links) => {
+                    extraction.edges.extend(links);
+                    write_edges(ctx, &id, &extraction).await
+                }
+                Err(e) => Err(e),
+            };
+        let error = written.err().map(|e| e.to_string());
+        report.push(BulkItem {
+            index,
+            id: Some(id),
+            replaced,
+            error,
+        });
+    }
+    Ok(())
+}
+
+impl From<Note> for Article {
+    fn from(note: Note) -> Self {
+        let links = note.link_targets();
+        Article {
+            id: Some(note.id),
+            stub: None,
+            title: note.title,
+            url: note.url,
+            body: note.body,
+            description: note.description,
+            tags: Some(note.tags).filter(|tags| !tags.is_empty()),
+            links: Some(links).filter(|links| !links.is_empty()),
+            published: note.published,
+        }
+    }
+}
+
+/// A directory to import notes from and the haystack root it lies in
+#[derive(Debug, Clone, PartialEq)]
+pub struct NoteDir {
+    pub dir: PathBuf,
+    /// Outermost haystack of any role containing `dir`, note ids are
+    /// relative to it so importing a subdirectory, or the same notes as
+    /// another role, updates the same articles
+    pub root: PathBuf,
+}
+
+fn canonical_haystacks(role: &Role) -> Vec<PathBuf> {
+    role.haystacks()
+        .into_iter()
+        .filter_map(|h| expand_home(h).canonicalize().ok())
+        .collect()
+}
+
+/// Directories to import notes from: the role's haystacks, or `path` when it
+/// lies inside one of them. Paths outside the haystacks are refused so the
+/// endpoint can't be used to read arbitrary files of the server.
+pub fn markdown_dirs(
+    roles: &RoleRegistry,
+    role: &Role,
+    path: Option<&str>,
+) -> Result<Vec<NoteDir>, ApiError> {
+    let haystacks = canonical_haystacks(role);
+    let dirs = match path.filter(|p| !p.is_empty()) {
+        // nested haystacks would import the same notes twice
+        None => outermost_dirs(&haystacks),
+        Some(path) => {
+            let dir = expand_home(path)
+                .canonicalize()
+                .map_err(|e| ApiError::BadQuery(format!("can't read {}: {}", path, e)))?;
+            if !haystacks.iter().any(|h| dir.starts_with(h)) {
+                return Err(ApiError::BadQuery(format!(
+                    "{} is not inside a haystack of role {}",
+                    path, role.shortname
+                )));
+            }
+            vec![dir]
+        }
+    };
+    let all: Vec<PathBuf> = roles.iter().flat_map(canonical_haystacks).collect();
+    let roots = outermost_dirs(&all);
+    Ok(dirs
+        .into_iter()
+        .map(|dir| NoteDir {
+            root: roots
+                .iter()
+                .find(|root| dir.starts_with(root))
+                .cloned()
+                .unwrap_or_else(|| dir.clone()),
+            dir,
+        })
+        .collect())
+}
+
+/// Parses the notes of `dirs` into articles. Notes which fail to parse are
+/// kept as errors naming the file.
+pub fn read_notes(dirs: &[NoteDir]) -> Result<Vec<Result<Article, String>>, ApiError> {
+    let mut articles = Vec::new();
+    for NoteDir { dir, root } in dirs {
+        let notes = parse_dir_in(dir, root).map_err(|e| ApiError::BadQuery(e.to_string()))?;
+        for (path, note) in notes {
+            articles.push(
+                note.map(|mut note| {
+                    // without an url in the front matter the note points at its file
+                    if Path::new(&note.url) == path {
+                        note.url = file_url(&root.join(&path));
+                    }
+                    Article::from(note)
+                })
+                .map_err(|e| e.to_string()),
+            );
+        }
+    }
+    Ok(articles)
+}
+
+fn file_url(path: &Path) -> String {
+    format!("file://{}", path.display())
+}
+
+/// Parses one line of a NDJSON body, `None` for blank lines
+pub fn parse_ndjson_line(line: &str) -> Option<Result<Article, String>> {
+    let line = line.trim();
+    if line.is_empty() {
+        return None;
+    }
+    Some(serde_json::from_str(line).map_err(|e| format!("invalid article: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ndjson_lines() {
+        let input = std::fs::read_to_string("test-data/org_project.json").unwrap();
+        let articles: Vec<serde_json::Value> = serde_json::from_str(&input).unwrap();
+        let ndjson: Vec<String> = articles.iter().map(|a| a.to_string()).collect();
+        let article = parse_ndjson_line(&ndjson[0]).unwrap().unwrap();
+        assert!(article.title.starts_with("Organization Strategic Plan"));
+        assert!(parse_ndjson_line("   ").is_none());
+        assert!(parse_ndjson_line("{\"title\": 1}").unwrap().is_err());
+    }
+
+    #[test]
+    fn test_edge_year_and_rank() {
+        // 2021-06-30T00:00:00Z
+        let created = 1625011200;
+        assert_eq!(edge_year(Some("2019-03-01"), created), 2019);
+        assert_eq!(edge_year(Some(" 2020 "), created), 2020);
+        assert_eq!(edge_year(Some("1625011200"), created), 2021);
+        assert_eq!(edge_year(None, created), 2021);
+
+        assert_eq!(edge_rank("swot", "scheduling", 0), 1.0);
+        assert_eq!(edge_rank("swot", "scheduling", 5), 0.5);
+        assert_eq!(edge_rank("project scheduling", "swot", 0), 2.0);
+        let sentence = "the swot informs project scheduling";
+        assert_eq!(words_between(sentence, Some((4, 8)), Some((17, 35))), 1);
+        assert_eq!(words_between(sentence, Some((17, 35)), Some((4, 8))), 1);
+        assert_eq!(words_between(sentence, None, Some((4, 8))), 0);
+    }
+
+    #[test]
+    fn test_note_ids_relative_to_haystack_root() {
+        let vault = std::env::temp_dir().join(format!("terraphim-vault-{}", std::process::id()));
+        std::fs::create_dir_all(vault.join("World")).unwrap();
+        std::fs::write(
+            vault.join("World/Rust.md"),
+            "# Rust\n\nRust has a borrow checker.",
+        )
+        .unwrap();
+        let config = format!(
+            r#"{{"roles": {{
+                "Default": {{"name": "Default", "relevance_function": "bm25", "serverUrl": "/search",
+                    "plugins": [{{"name": "terraphim-grep", "hackstack": "{vault}"}}]}},
+                "Father": {{"name": "Father", "relevance_function": "bm25", "serverUrl": "/search",
+                    "plugins": [{{"name": "terraphim-grep", "hackstack": "{vault}/World"}}]}}
+            }}}}"#,
+            vault = vault.display()
+        );
+        let roles = RoleRegistry::from_json(&config).unwrap();
+        let default = roles.get("default").unwrap();
+        let ids = |dirs: Vec<NoteDir>| -> Vec<Option<String>> {
+            read_notes(&dirs)
+                .unwrap()
+                .into_iter()
+                .map(|article| article.unwrap().id)
+                .collect()
+        };
+        let world = vault.join("World").display().to_string();
+        // the subdirectory first, then the parent
+        let subdirectory = ids(markdown_dirs(&roles, default, Some(&world)).unwrap());
+        let parent = ids(markdown_dirs(&roles, default, None).unwrap());
+        let father = ids(markdown_dirs(&roles, roles.get("father").unwrap(), None).unwrap());
+        assert_eq!(subdirectory, vec![Some("world-rust".to_string())]);
+        assert_eq!(parent, subdirectory);
+        assert_eq!(father, subdirectory);
+        std::fs::remove_dir_all(vault).unwrap();
+    }
+
+    #[test]
+    fn test_last_per_id() {
+        let article = |id: &str| {
+            let line = format!(r#"{{"id": "{id}", "title": "t", "url": "u", "body": "b"}}"#);
+            (id.to_string(), parse_ndjson_line(&line).unwrap().unwrap())
+        };
+        let articles = vec![article("a"), article("b"), article("a")]
+            .into_iter()
+            .enumerate()
+            .map(|(index, (id, article))| (index, id, article))
+            .collect();
+        let mut report = BulkReport::default();
+        let kept = last_per_id(articles, &mut report);
+        let kept: Vec<usize> = kept.iter().map(|(index, _, _)| *index).collect();
+        assert_eq!(kept, vec![1, 2]);
+        assert_eq!(report.failed, 1);
+        assert_eq!(report.items[0].index, 0);
+    }
+
+    #[test]
+    fn test_by_sentence() {
+        let body = "The swot is done. Then project scheduling starts.";
+        let sentences = split_paragraph_offsets(body);
+        let matched = |term: &str, pos| Matched {
+            term: term.to_string(),
+            id: term.to_string(),
+            nterm: term.to_string(),
+            pos: Some(pos),
+        };
+        let grouped = by_sentence(
+            &sentences,
+            vec![
+                matched("swot", (4, 8)),
+                matched("done then", (12, 23)),
+                matched("project scheduling", (23, 41)),
+            ],
+        );
+        let terms: Vec<Vec<&str>> = grouped
+            .iter()
+            .map(|ents| ents.iter().map(|e| e.term.as_str()).collect())
+            .collect();
+        // the match across the sentence boundary is dropped
+        assert_eq!(terms, vec![vec!["swot"], vec!["project scheduling"]]);
+    }
+
+    #[test]
+    fn test_created_from_id() {
+        let ulid = Ulid::new();
+        assert_eq!(
+            created_from_id(&ulid.to_string()),
+            ulid.timestamp_ms() / 1000
+        );
+        assert!(created_from_id("my-article") > 0);
+    }
+}
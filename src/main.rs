@@ -1,15 +1,20 @@
-use poem::{listener::TcpListener, web::Data, EndpointExt, Result, Route, Server};
-use poem_openapi::{param::Query, payload::PlainText, OpenApi, OpenApiService};
-use poem_openapi::{payload::Json, ApiResponse, Object, Tags};
+use poem::{listener::TcpListener, web::Data, Body, EndpointExt, Result, Route, Server};
+use poem_openapi::{
+    param::{Path, Query},
+    payload::{Binary, PlainText},
+    OpenApi, OpenApiService,
+};
+use poem_openapi::{payload::Json, ApiRequest, ApiResponse, Object, Tags};
 use serde::{Deserialize, Serialize};
-use std::error::Error;
-use itertools::Itertools;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::io::AsyncBufReadExt;
 extern crate config;
 extern crate serde;
 mod settings;
 
-use settings::Settings;
 use regex::Regex;
+use settings::Settings;
 
 #[macro_use]
 extern crate lazy_static;
@@ -17,20 +22,58 @@ lazy_static! {
     static ref RE: Regex = Regex::new(r"[?!|]\s+").unwrap();
 }
 
-use redis::{FromRedisValue, Value, Commands};
+use redis::{AsyncCommands, FromRedisValue, Value};
 use redis_derive::{FromRedisValue, ToRedisArgs};
 use ulid::Ulid;
 
-use terraphim_automata::{find_matches, load_automata, Dictionary, Matched};
-use terraphim_pipeline::split_paragraphs;
 mod graph_search;
-use graph_search::{get_edges, match_nodes, Edge};
+use graph_search::{
+    article_support, concept_name, edges_between, evidence, evidence_sentences, get_edges,
+    matched_synonyms, quoted, retract_article, EdgeFilter,
+};
+use terraphim_pipeline::graph::{concept_synonyms_key, valid_relation};
+mod roles;
+use roles::{Role, RoleError, RoleRegistry};
+mod automata;
+use automata::AutomataCache;
+mod error;
+use error::{ApiError, ApiResult};
+mod redis_pool;
+use redis_pool::Pool;
+mod search_index;
+use search_index::INDEX_ALIAS;
+mod redisearch;
+use redisearch::{parse_redisearch_response, RedisearchResult, ReplyLayout, SearchPage};
+mod search_query;
+use search_query::SearchFilter;
+mod hybrid;
+use hybrid::fusion_method;
+mod relevance;
+use relevance::{relevance_function, RankContext};
+mod annotate;
+use annotate::{render_html, spans, ConceptSpan};
+mod haystack;
+use haystack::{haystacks, search_haystacks};
+mod ingest;
+use ingest::{
+    created_from_id, ingest_batch, markdown_dirs, parse_article, parse_ndjson_line, read_notes,
+    BulkReport, EdgeWrites, IngestContext, Sentence, BULK_BATCH_SIZE,
+};
+use terraphim_automata::find_matches;
+
+/// Candidates taken from each ranking before `/hsearch/` fuses them
+const HYBRID_CANDIDATES: usize = 100;
+
+/// Sentences returned per supporting article by `/edges/:source/:target`
+const EDGE_EVIDENCE_LIMIT: usize = 10;
 
 #[derive(Tags)]
 enum ApiTags {
     /// Operations about articles
     Article,
     SearchQuery,
+    /// Role automata management
+    Automata,
 }
 
 /// Create article schema
@@ -43,6 +86,11 @@ struct Article {
     body: String,
     description: Option<String>,
     tags: Option<Vec<String>>,
+    /// Targets of the article's `[[wikilinks]]`: concept names or article titles
+    links: Option<Vec<String>>,
+    /// Publication date as `YYYY-MM-DD` or a year, the year of the
+    /// article's edges. Edges of articles without one get the year it was stored.
+    published: Option<String>,
 }
 
 #[derive(Debug, Object)]
@@ -51,83 +99,222 @@ struct SearchQuery {
     skip: usize,
     limit: usize,
     role: Option<String>,
+    /// Return the relevance score of every result
+    with_scores: Option<bool>,
+    /// Wrap matched terms of the returned title and body in `<b>` tags
+    highlight: Option<bool>,
+    /// Return only the fragments of the body around the matched terms, in place of the body
+    summarize: Option<bool>,
+    /// Pass `search_term` to RediSearch as query syntax instead of escaping it
+    raw: Option<bool>,
+    /// Field, tag and date restrictions and the sort order
+    filter: Option<SearchFilter>,
+    /// Relationship types graph searches follow, e.g. `LINKS_TO`; all when missing
+    relations: Option<Vec<String>>,
+    /// Years of the edges graph searches follow, all when missing
+    years: Option<Vec<i64>>,
 }
 
-//  FT.CREATE ArticleIdx ON HASH PREFIX 1 article: SCHEMA title TEXT WEIGHT 5.0 body TEXT url TEXT
+impl SearchQuery {
+    /// Whether the query uses options only the article index supports
+    fn index_only(&self) -> bool {
+        self.filter.is_some()
+            || self.raw.unwrap_or(false)
+            || self.with_scores.unwrap_or(false)
+            || self.highlight.unwrap_or(false)
+            || self.summarize.unwrap_or(false)
+    }
 
-// TODO: check if can be rewritten nice with https://docs.rs/struct-field-names-as-array/latest/struct_field_names_as_array/ or macros
-#[derive(Object, Serialize, Deserialize, Debug)]
-pub struct RedisearchResult {
+    fn relations(&self) -> Result<Option<&[String]>, ApiError> {
+        let relations = match &self.relations {
+            Some(relations) => relations,
+            None => return Ok(None),
+        };
+        match relations.iter().find(|r| !valid_relation(r)) {
+            Some(r) => Err(ApiError::BadQuery(format!(
+                "invalid relationship type {}",
+                r
+            ))),
+            None => Ok(Some(relations.as_slice())),
+        }
+    }
+}
+
+/// Article ranked by graph search
+#[derive(Debug, Object)]
+struct SearchResult {
     id: String,
-    stub: Option<String>,
     title: String,
     url: String,
-    body: String,
-    description: Option<String>,
-    tags: Option<Vec<String>>,
+    /// Score given by the role's relevance function
+    rank: f64,
+    /// Graph edges the article was found through, empty for text rankings
+    relationships: Vec<Relationship>,
 }
 
-impl FromRedisValue for RedisearchResult {
-    fn from_redis_value(v: &Value) -> redis::RedisResult<Self> {
-        let values: Vec<String> = redis::from_redis_value(v)?;
-        let mut id = String::new();
-        let mut title = String::new();
-        let mut stub = String::new();
-        let mut url = String::new();
-        let mut body = String::new();
-        let mut description = String::new();
-        let mut tags = vec![<String>::new()];
-        println!(" Values - - - {:?}", values);
-        for i in 0..values.len() {
-            match values[i].as_str() {
-                "id" => id = values[i + 1].clone(),
-                "title" => title = values[i + 1].clone(),
-                "stub" => stub = values[i + 1].clone(),
-                "url" => url = values[i + 1].clone(),
-                "body" => body = values[i + 1].clone(),
-                "description" => description = values[i + 1].clone(),
-                "tags" => {
-                    tags = values[i + 1]
-                        .clone()
-                        .split(',')
-                        .map(|s| s.trim().to_string())
-                        .collect()
-                }
-                _ => continue,
-            }
-        }
+/// Graph edge with the sentence of the article it was found in
+#[derive(Debug, Object)]
+struct Relationship {
+    source: String,
+    target: String,
+    source_name: Option<String>,
+    target_name: Option<String>,
+    relation: Option<String>,
+    rank: f64,
+    year: Option<i64>,
+    /// Sentence of the article where the edge ranked highest, missing for
+    /// links and articles ingested before sentences were kept
+    evidence: Option<Sentence>,
+}
 
-        Ok(RedisearchResult {
-            id,
-            stub: stub.parse().ok(),
-            title,
-            url,
-            body,
-            description: description.parse().ok(),
-            tags: Some(tags),
-        })
-    }
+/// Concept of the role graph
+#[derive(Debug, Object, PartialEq, Eq, PartialOrd, Ord)]
+struct Concept {
+    id: String,
+    /// Normalised term
+    name: Option<String>,
+    /// Synonyms in the search term which matched the concept
+    synonyms: Vec<String>,
 }
 
-pub fn parse_redisearch_response(response: &Value) -> Vec<RedisearchResult> {
-    match response {
-        Value::Bulk(array) => {
-            let mut results = Vec::new();
-            let n = array.len();
-
-            for item in array.iter().take(n).skip(1) {
-                if let Value::Bulk(ref bulk) = item {
-                    if let Ok(result) =
-                        RedisearchResult::from_redis_value(&Value::Bulk(bulk.clone()))
-                    {
-                        results.push(result);
-                    }
-                }
-            }
+/// How often a synonym of a concept occurs in the stored articles
+#[derive(Debug, Object)]
+struct SynonymUsage {
+    term: String,
+    count: i64,
+}
 
-            results
-        }
-        _ => vec![],
+/// Concept of the role thesaurus with the usage of each of its synonyms
+#[derive(Debug, Object)]
+struct ConceptSynonyms {
+    id: String,
+    /// Normalised term
+    name: String,
+    /// Most used first
+    synonyms: Vec<SynonymUsage>,
+}
+
+#[derive(ApiResponse)]
+enum ConceptResponse {
+    /// Returns the concept.
+    #[oai(status = 200)]
+    Ok(Json<ConceptSynonyms>),
+    /// Return when the role has no concept with this id.
+    #[oai(status = 404)]
+    NotFound,
+}
+
+/// Article an edge was found in
+#[derive(Debug, Object)]
+struct SupportingArticle {
+    id: String,
+    title: String,
+    url: String,
+    /// What the article adds to the edge score
+    score: f64,
+    /// Sentences the concepts co-occur in, highest rank first
+    sentences: Vec<Sentence>,
+}
+
+/// Why two concepts are linked
+#[derive(Debug, Object)]
+struct EdgeExplanation {
+    source: String,
+    target: String,
+    /// Normalised term, or the title when the source is an article
+    source_name: Option<String>,
+    /// Normalised term, or the title when the target is an article
+    target_name: Option<String>,
+    /// Sum of the article scores in `edges_scored:{source}:{target}`
+    score: f64,
+    /// Years of the relationships in the role graph, oldest first
+    years: Vec<i64>,
+    /// Relationship types in the role graph, e.g. `CO_OCCURS`
+    relations: Vec<String>,
+    /// Highest score first
+    articles: Vec<SupportingArticle>,
+}
+
+#[derive(ApiResponse)]
+enum EdgeResponse {
+    /// Returns the edge.
+    #[oai(status = 200)]
+    Ok(Json<EdgeExplanation>),
+    /// Return when no article links the concepts.
+    #[oai(status = 404)]
+    NotFound,
+}
+
+/// Article ranked by the fusion of full-text and graph search
+#[derive(Debug, Object)]
+struct HybridResult {
+    id: String,
+    title: String,
+    url: String,
+    /// Fused score, only comparable within one response
+    score: f64,
+    /// FT.SEARCH score, missing when only the graph search found the article
+    text_score: Option<f64>,
+    /// Graph score, missing when only the full-text search found the article
+    graph_score: Option<f64>,
+    /// Concepts of the graph edges the article was found through
+    concepts: Vec<Concept>,
+}
+
+/// Text to annotate with the concepts of a role
+#[derive(Debug, Object)]
+struct AnnotateRequest {
+    /// Text to annotate, or
+    text: Option<String>,
+    /// id of a stored article whose body is annotated
+    article_id: Option<String>,
+    role: Option<String>,
+    /// Also render the text as HTML with linked concepts
+    html: Option<bool>,
+}
+
+/// Text with the concepts found in it
+#[derive(Debug, Object)]
+struct Annotation {
+    text: String,
+    /// Non-overlapping concept spans ordered by position
+    spans: Vec<ConceptSpan>,
+    html: Option<String>,
+}
+
+#[derive(ApiResponse)]
+enum AnnotateResponse {
+    /// Returns the annotated text.
+    #[oai(status = 200)]
+    Ok(Json<Annotation>),
+    /// Return when the specified article is not found.
+    #[oai(status = 404)]
+    NotFound,
+}
+
+/// Outcome of an automata reload
+#[derive(Debug, Object)]
+struct AutomataReload {
+    /// Roles with an automata available after the reload
+    loaded: Vec<String>,
+    /// Roles which failed to reload and kept their previous automata
+    failed: Vec<String>,
+}
+
+/// The configured roles with their automata, shared with handlers through poem `Data`
+#[derive(Clone)]
+struct Roles {
+    registry: RoleRegistry,
+    automata: Arc<AutomataCache>,
+    /// Role used when a request does not name one
+    default_role: String,
+}
+
+impl Roles {
+    /// Resolves the requested role, falling back to the configured default role
+    fn resolve(&self, role: Option<&str>) -> Result<&Role, RoleError> {
+        let role = role.filter(|r| !r.is_empty()).unwrap_or(&self.default_role);
+        self.registry.get(role)
     }
 }
 
@@ -138,79 +325,52 @@ enum CreateArticleResponse {
     Ok(Json<String>),
 }
 
+#[derive(ApiResponse)]
+enum ReindexResponse {
+    /// Returns when the new index version is being built.
+    #[oai(status = 202)]
+    Accepted,
+    /// Returns when a reindex is already running.
+    #[oai(status = 409)]
+    Conflict,
+}
+
 #[derive(ApiResponse)]
 enum FindArticleResponse {
-    /// Return the specified user.
+    /// Return the specified article.
     #[oai(status = 200)]
     Ok(Json<Article>),
-    /// Return when the specified user is not found.
+    /// Return when the specified article is not found.
     #[oai(status = 404)]
     NotFound,
 }
 #[derive(ApiResponse)]
 enum DeleteArticleResponse {
-    /// Returns when the user is successfully deleted.
+    /// Returns when the article is successfully deleted.
     #[oai(status = 200)]
     Ok,
-    /// Return when the specified user is not found.
+    /// Return when the specified article is not found.
     #[oai(status = 404)]
     NotFound,
 }
 
 #[derive(ApiResponse)]
 enum UpdateArticleResponse {
-    /// Returns when the user is successfully updated.
+    /// Returns when the article is successfully updated.
     #[oai(status = 200)]
     Ok,
-    /// Return when the specified user is not found.
+    /// Return when the specified article is not found.
     #[oai(status = 404)]
     NotFound,
 }
 
-async fn parse_article(article: &Article, id:&str,role: &str, automata_url: &str, con: &mut redis::Connection) -> redis::RedisResult<()>{
-    
-    for sentence in split_paragraphs(&article.body) {
-        println!("{}", sentence);
-        let shard_id= "{06S}";
-        // for each role run
-        // println!("Role {}", role);
-        // let automata_url = "./crates/terraphim_automata/data/output.csv.gz";
-        let automata = load_automata(automata_url).unwrap();
-        let matched_ents = find_matches(sentence, automata, false).expect("Failed to find matches");
-        println!("Nodes {:?}", matched_ents);
-        
-        for pair in matched_ents.into_iter().combinations(2) {
-            println!("Pair {:?}", pair);
-            let source_entity_id = pair[0].id.clone();
-            let destination_entity_id = pair[1].id.clone();
-            let source_canonical_name = pair[0].term.clone();
-            let destination_canonical_name = pair[1].term.clone();
-            
-            let _: () = redis::cmd("XADD")
-            .arg(format!("edges_matched_{role}_{shard_id}"))
-            .arg("*")
-            .arg("source")
-            .arg(source_entity_id.clone())
-            .arg("destination")
-            .arg(destination_entity_id.clone())
-            .arg("source_name")
-            .arg(source_canonical_name)
-            .arg("destination_name")
-            .arg(destination_canonical_name)
-            .arg("rank")
-            .arg(1)
-            .arg("year")
-            .arg(2023)
-            .execute(con);
-            let _: () = redis::cmd("ZINCRBY")
-            .arg(format!("edges_scored:{}:{}",source_entity_id,destination_entity_id))
-            .arg(1)
-            .arg(&id)
-            .execute(con);
-
-        }
-    }
-    Ok(())
+#[derive(ApiRequest)]
+enum BulkArticles {
+    /// JSON array of articles
+    Json(Json<Vec<Article>>),
+    /// One JSON article per line, read as it streams in
+    #[oai(content_type = "application/x-ndjson")]
+    Ndjson(Binary<Body>),
 }
 
 struct Api;
@@ -227,26 +387,40 @@ impl Api {
     #[oai(path = "/articles", method = "post", tag = "ApiTags::Article")]
     async fn create_article(
         &self,
-        settings: Data<&Settings>,
+        pool: Data<&Pool>,
+        roles: Data<&Roles>,
+        writes: Data<&EdgeWrites>,
+        role: Query<Option<String>>,
         article: Json<Article>,
-    ) -> CreateArticleResponse {
-        let id = Ulid::new().to_string();
+    ) -> ApiResult<CreateArticleResponse> {
+        let role = roles.resolve(role.0.as_deref())?;
+        let automata = roles.automata.get(role)?;
+        let ulid = Ulid::new();
+        let id = ulid.to_string();
+        let created = ulid.timestamp_ms() / 1000;
 
-        let url = settings.redis_url.clone();
-        let client = redis::Client::open(url).unwrap();
-        let mut con = client.get_connection().unwrap();
+        let mut con = pool.get().await?;
         // println!("Aricle {:?}",article);
         let _: () = redis::cmd("HSET")
             .arg(format!("article:{}", id))
             .arg(&*article)
-            .query(&mut con)
-            .unwrap();
-        let role= "project-manager";
-        let automata_url = "./test-data/term_to_id.json";
-        let _ = parse_article(&article, &id, role, automata_url, &mut con).await;
-        // let nodes = vec![settings.redis_cluster_url.clone(),"redis://127.0.0.1:30002/".to_string()];
-        // let cluster_client = ClusterClient::new(nodes).unwrap();
-        // let mut cluster_connection = cluster_client.get_async_connection().await.unwrap();
+            .arg("created")
+            .arg(created)
+            .query_async(&mut con)
+            .await?;
+        let mut ctx = IngestContext {
+            con: &mut con,
+            role,
+            automata,
+            writes: &writes,
+        };
+        let parsed = parse_article(&mut ctx, &article, &id, created).await;
+        if let Err(e) = parsed {
+            // a retry stores the article again under a new id, so don't keep this one
+            retract_article(&mut con, &id).await?;
+            let _: () = con.del(format!("article:{}", id)).await?;
+            return Err(e.into());
+        }
         // let body = article.body.split('\n').collect::<Vec<&str>>().join(" ");
         // let _: () = con.set(format!("paragraphs:{}",&id),body).await.unwrap();
         // split paragraph by stentences
@@ -257,88 +431,642 @@ impl Api {
         //     .query(&mut con)
         //     .unwrap();
 
-        CreateArticleResponse::Ok(Json(id))
+        Ok(CreateArticleResponse::Ok(Json(id)))
+    }
+
+    /// Import many articles at once. Articles carrying an `id` replace the
+    /// stored article with that id, so an import can be safely repeated.
+    #[oai(path = "/articles/bulk", method = "post", tag = "ApiTags::Article")]
+    async fn bulk_import(
+        &self,
+        pool: Data<&Pool>,
+        roles: Data<&Roles>,
+        writes: Data<&EdgeWrites>,
+        role: Query<Option<String>>,
+        articles: BulkArticles,
+    ) -> ApiResult<Json<BulkReport>> {
+        let role = roles.resolve(role.0.as_deref())?;
+        let automata = roles.automata.get(role)?;
+        let mut con = pool.get().await?;
+        let mut ctx = IngestContext {
+            con: &mut con,
+            role,
+            automata,
+            writes: &writes,
+        };
+        let mut report = BulkReport::default();
+        match articles {
+            BulkArticles::Json(Json(articles)) => {
+                let mut articles = articles.into_iter().map(Ok).enumerate().peekable();
+                while articles.peek().is_some() {
+                    let batch: Vec<_> = articles.by_ref().take(BULK_BATCH_SIZE).collect();
+                    ingest_batch(&mut ctx, batch, &mut report).await?;
+                }
+            }
+            BulkArticles::Ndjson(Binary(body)) => {
+                let mut lines = tokio::io::BufReader::new(body.into_async_read()).lines();
+                let mut batch = Vec::new();
+                let mut index = 0;
+                loop {
+                    let line = lines
+                        .next_line()
+                        .await
+                        .map_err(|e| ApiError::BadQuery(format!("failed to read body: {}", e)))?;
+                    let done = line.is_none();
+                    if let Some(article) = line.as_deref().and_then(parse_ndjson_line) {
+                        batch.push((index, article));
+                    }
+                    index += 1;
+                    if batch.len() >= BULK_BATCH_SIZE || (done && !batch.is_empty()) {
+                        let full = std::mem::take(&mut batch);
+                        ingest_batch(&mut ctx, full, &mut report).await?;
+                    }
+                    if done {
+                        break;
+                    }
+                }
+            }
+        }
+        println!(
+            "Bulk import stored {} failed {}",
+            report.stored, report.failed
+        );
+        Ok(Json(report))
+    }
+
+    /// Import the Markdown notes of the role's haystacks, or of `path` when
+    /// it lies inside one of them. Notes keep their id across imports.
+    #[oai(path = "/articles/markdown", method = "post", tag = "ApiTags::Article")]
+    async fn import_markdown(
+        &self,
+        pool: Data<&Pool>,
+        roles: Data<&Roles>,
+        writes: Data<&EdgeWrites>,
+        role: Query<Option<String>>,
+        path: Query<Option<String>>,
+    ) -> ApiResult<Json<BulkReport>> {
+        let role = roles.resolve(role.0.as_deref())?;
+        let automata = roles.automata.get(role)?;
+        let dirs = markdown_dirs(&roles.registry, role, path.0.as_deref())?;
+        println!("Importing notes from {:?}", dirs);
+        let notes = tokio::task::spawn_blocking(move || read_notes(&dirs))
+            .await
+            .map_err(|e| ApiError::Internal(e.to_string()))??;
+        let mut con = pool.get().await?;
+        let mut ctx = IngestContext {
+            con: &mut con,
+            role,
+            automata,
+            writes: &writes,
+        };
+        let mut report = BulkReport::default();
+        let mut notes = notes.into_iter().enumerate().peekable();
+        while notes.peek().is_some() {
+            let batch: Vec<_> = notes.by_ref().take(BULK_BATCH_SIZE).collect();
+            ingest_batch(&mut ctx, batch, &mut report).await?;
+        }
+        println!(
+            "Markdown import stored {} failed {}",
+            report.stored, report.failed
+        );
+        Ok(Json(report))
+    }
+
+    /// Find article by id
+    #[oai(path = "/articles/:id", method = "get", tag = "ApiTags::Article")]
+    async fn get_article(
+        &self,
+        pool: Data<&Pool>,
+        id: Path<String>,
+    ) -> ApiResult<FindArticleResponse> {
+        let mut con = pool.get().await?;
+        let value: Value = con.hgetall(format!("article:{}", id.0)).await?;
+        if matches!(&value, Value::Bulk(fields) if fields.is_empty()) {
+            return Ok(FindArticleResponse::NotFound);
+        }
+        let mut article = Article::from_redis_value(&value)?;
+        article.id = Some(id.0);
+        Ok(FindArticleResponse::Ok(Json(article)))
+    }
+
+    /// Replace an article, retracting its old edges and extracting the new ones
+    #[oai(path = "/articles/:id", method = "put", tag = "ApiTags::Article")]
+    async fn update_article(
+        &self,
+        pool: Data<&Pool>,
+        roles: Data<&Roles>,
+        writes: Data<&EdgeWrites>,
+        id: Path<String>,
+        role: Query<Option<String>>,
+        article: Json<Article>,
+    ) -> ApiResult<UpdateArticleResponse> {
+        let role = roles.resolve(role.0.as_deref())?;
+        let automata = roles.automata.get(role)?;
+        let mut con = pool.get().await?;
+        let key = format!("article:{}", id.0);
+        let exists: bool = con.exists(&key).await?;
+        if !exists {
+            return Ok(UpdateArticleResponse::NotFound);
+        }
+        retract_article(&mut con, &id.0).await?;
+        let created: Option<u64> = con.hget(&key, "created").await?;
+        let created = created.unwrap_or_else(|| created_from_id(&id.0));
+        let mut pipe = redis::pipe();
+        pipe.atomic()
+            .del(&key)
+            .ignore()
+            .cmd("HSET")
+            .arg(&key)
+            .arg(&*article)
+            .arg("created")
+            .arg(created)
+            .ignore();
+        pipe.query_async::<_, ()>(&mut con).await?;
+        let mut ctx = IngestContext {
+            con: &mut con,
+            role,
+            automata,
+            writes: &writes,
+        };
+        parse_article(&mut ctx, &article, &id.0, created).await?;
+        Ok(UpdateArticleResponse::Ok)
+    }
+
+    /// Delete an article together with its contributions to scores and graph
+    #[oai(path = "/articles/:id", method = "delete", tag = "ApiTags::Article")]
+    async fn delete_article(
+        &self,
+        pool: Data<&Pool>,
+        id: Path<String>,
+    ) -> ApiResult<DeleteArticleResponse> {
+        let mut con = pool.get().await?;
+        let key = format!("article:{}", id.0);
+        let exists: bool = con.exists(&key).await?;
+        if !exists {
+            return Ok(DeleteArticleResponse::NotFound);
+        }
+        retract_article(&mut con, &id.0).await?;
+        let _: () = con.del(&key).await?;
+        Ok(DeleteArticleResponse::Ok)
     }
 
     #[oai(path = "/rsearch/", method = "post", tag = "ApiTags::SearchQuery")]
     async fn graph_search(
         &self,
-        settings: Data<&Settings>,
+        pool: Data<&Pool>,
+        roles: Data<&Roles>,
+        search_query: Json<SearchQuery>,
+    ) -> ApiResult<Json<Vec<SearchResult>>> {
+        tracing::debug!("{:?}", search_query);
+        let role = roles.resolve(search_query.role.as_deref())?;
+        let automata = roles.automata.get(role)?;
+        let relevance = relevance_function(role)?;
+        tracing::debug!(
+            "Role {}, relevance function {}",
+            role.shortname,
+            relevance.name()
+        );
+        let mut con = pool.get().await?;
+        let ranked = relevance
+            .rank(&mut RankContext {
+                con: &mut con,
+                role,
+                automata: &automata,
+                search_term: &search_query.search_term,
+                limit: search_query.skip + search_query.limit,
+                relations: search_query.relations()?,
+                years: search_query.years.as_deref(),
+            })
+            .await?;
+        let mut results = Vec::new();
+        for ranked in ranked
+            .into_iter()
+            .skip(search_query.skip)
+            .take(search_query.limit)
+        {
+            let (title, url): (Option<String>, Option<String>) = con
+                .hget(format!("article:{}", ranked.id), &["title", "url"])
+                .await?;
+            // article was removed after its edges were scored
+            let title = match title {
+                Some(title) => title,
+                None => continue,
+            };
+            let mut relationships = Vec::new();
+            for edge in ranked.edges {
+                relationships.push(Relationship {
+                    evidence: evidence(&mut con, &edge, &ranked.id).await?,
+                    source: edge.e_id,
+                    target: edge.t_id,
+                    source_name: edge.e_name,
+                    target_name: edge.t_name,
+                    relation: edge.relation,
+                    rank: edge.rank,
+                    year: edge.year,
+                });
+            }
+            results.push(SearchResult {
+                id: ranked.id,
+                title,
+                url: url.unwrap_or_default(),
+                rank: ranked.score,
+                relationships,
+            });
+        }
+
+        Ok(Json(results))
+    }
+
+    /// Search full-text and the role graph at once and fuse both rankings
+    /// as configured by the role's `relevance_function`
+    #[oai(path = "/hsearch/", method = "post", tag = "ApiTags::SearchQuery")]
+    async fn hybrid_search(
+        &self,
+        pool: Data<&Pool>,
+        roles: Data<&Roles>,
         search_query: Json<SearchQuery>,
-    ) -> Json<Vec<String>> {
+    ) -> ApiResult<Json<Vec<HybridResult>>> {
         println!("{:#?}", search_query);
-        let role = search_query.role.as_deref().unwrap_or("");
-        println!("Role {}", role);
-        let automata_url = "./test-data/term_to_id.json";
-        let automata = load_automata(automata_url).unwrap();
-        let nodes = match_nodes(&search_query.search_term, automata);
-        println!("Nodes {:?}", nodes);
-        let links = get_edges(&settings, &nodes, None, 50);
-        println!("Links {:?}", links);
-
-        Json(nodes)
+        let role = roles.resolve(search_query.role.as_deref())?;
+        let automata = roles.automata.get(role)?;
+        let method = fusion_method(role)?;
+        let candidates = (search_query.skip + search_query.limit).max(HYBRID_CANDIDATES);
+        let mut con = pool.get().await?;
+
+        let query = search_query::compile(
+            &search_query.search_term,
+            search_query.raw.unwrap_or(false),
+            search_query.filter.as_ref(),
+        )?;
+        let mut cmd = redis::cmd("FT.SEARCH");
+        cmd.arg(INDEX_ALIAS);
+        query.apply(&mut cmd);
+        cmd.arg("WITHSCORES")
+            .arg("RETURN")
+            .arg(2)
+            .arg("title")
+            .arg("url")
+            .arg("LIMIT")
+            .arg(0)
+            .arg(candidates);
+        let values: Value = cmd.query_async(&mut con).await?;
+        let layout = ReplyLayout { with_scores: true };
+        let (_, text_results) = parse_redisearch_response(&values, &layout)?;
+
+        // one match of the search term gives both the concepts and their synonyms
+        let synonyms = matched_synonyms(&search_query.search_term, &automata)?;
+        let nodes = quoted(&synonyms.keys().cloned().collect());
+        let filter = EdgeFilter {
+            years: search_query.years.as_deref(),
+            relations: search_query.relations()?,
+        };
+        let edges = get_edges(&mut con, &role.graph_name, &nodes, filter, 50).await?;
+        let support = article_support(&mut con, &edges).await?;
+        let mut graph_ranking: Vec<(String, f64)> = support
+            .iter()
+            .map(|(id, article)| (id.clone(), article.score))
+            .collect();
+        graph_ranking.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        graph_ranking.truncate(candidates);
+
+        let text_ranking: Vec<(String, f64)> = text_results
+            .iter()
+            .map(|r| (r.id.clone(), r.score.unwrap_or_default()))
+            .collect();
+        let titles: HashMap<&str, (&str, &str)> = text_results
+            .iter()
+            .map(|r| (r.id.as_str(), (r.title.as_str(), r.url.as_str())))
+            .collect();
+        let fused = hybrid::fuse(&text_ranking, &graph_ranking, &method);
+
+        let mut results = Vec::new();
+        for f in fused
+            .into_iter()
+            .skip(search_query.skip)
+            .take(search_query.limit)
+        {
+            let (title, url) = match titles.get(f.id.as_str()) {
+                Some((title, url)) => (title.to_string(), url.to_string()),
+                None => {
+                    let (title, url): (Option<String>, Option<String>) = con
+                        .hget(format!("article:{}", f.id), &["title", "url"])
+                        .await?;
+                    // article was removed after its edges were scored
+                    match title {
+                        Some(title) => (title, url.unwrap_or_default()),
+                        None => continue,
+                    }
+                }
+            };
+            let mut concepts: Vec<Concept> = Vec::new();
+            if let Some(article) = support.get(&f.id) {
+                for edge in article.edges.iter().map(|i| &edges[*i]) {
+                    for (id, name) in [(&edge.e_id, &edge.e_name), (&edge.t_id, &edge.t_name)] {
+                        concepts.push(Concept {
+                            id: id.clone(),
+                            name: name.clone(),
+                            synonyms: synonyms.get(id).cloned().unwrap_or_default(),
+                        });
+                    }
+                }
+            }
+            concepts.sort();
+            concepts.dedup();
+            results.push(HybridResult {
+                id: f.id,
+                title,
+                url,
+                score: f.score,
+                text_score: f.text_score,
+                graph_score: f.graph_score,
+                concepts,
+            });
+        }
+        Ok(Json(results))
+    }
+
+    /// Find the concepts of the role in a text or article body, with their
+    /// positions and normalised terms
+    #[oai(path = "/annotate", method = "post", tag = "ApiTags::Automata")]
+    async fn annotate(
+        &self,
+        settings: Data<&Settings>,
+        pool: Data<&Pool>,
+        roles: Data<&Roles>,
+        request: Json<AnnotateRequest>,
+    ) -> ApiResult<AnnotateResponse> {
+        let role = roles.resolve(request.role.as_deref())?;
+        let automata = roles.automata.get(role)?;
+        let text = match (&request.text, &request.article_id) {
+            (Some(text), _) => text.clone(),
+            (None, Some(id)) => {
+                let mut con = pool.get().await?;
+                let body: Option<String> = con.hget(format!("article:{}", id), "body").await?;
+                match body {
+                    Some(body) => body,
+                    None => return Ok(AnnotateResponse::NotFound),
+                }
+            }
+            (None, None) => {
+                return Err(
+                    ApiError::BadQuery("either text or article_id is required".to_string()).into(),
+                )
+            }
+        };
+        let matches = find_matches(&text, (*automata).clone(), true)
+            .map_err(|e| ApiError::Matching(e.to_string()))?;
+        let spans = spans(&text, matches);
+        let html = match request.html {
+            Some(true) => Some(render_html(&text, &spans, &settings.concept_link)),
+            _ => None,
+        };
+        Ok(AnnotateResponse::Ok(Json(Annotation { text, spans, html })))
+    }
+
+    /// A concept of the role with its synonyms and how often each occurs
+    /// in the stored articles
+    #[oai(path = "/concepts/:id", method = "get", tag = "ApiTags::Automata")]
+    async fn get_concept(
+        &self,
+        pool: Data<&Pool>,
+        roles: Data<&Roles>,
+        id: Path<String>,
+        role: Query<Option<String>>,
+    ) -> ApiResult<ConceptResponse> {
+        let role = roles.resolve(role.0.as_deref())?;
+        let automata = roles.automata.get(role)?;
+        let terms: Vec<(&String, &String)> = automata
+            .iter()
+            .filter(|(_, dict)| dict.id == id.0)
+            .map(|(term, dict)| (term, &dict.nterm))
+            .collect();
+        let name = match terms.first() {
+            Some((_, nterm)) => nterm.to_string(),
+            None => return Ok(ConceptResponse::NotFound),
+        };
+        let mut con = pool.get().await?;
+        let counts: HashMap<String, i64> = con
+            .hgetall(concept_synonyms_key(&role.graph_name, &id.0))
+            .await?;
+        let mut synonyms: Vec<SynonymUsage> = terms
+            .iter()
+            .map(|(term, _)| SynonymUsage {
+                term: term.to_string(),
+                count: counts.get(*term).copied().unwrap_or_default().max(0),
+            })
+            .collect();
+        synonyms.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.term.cmp(&b.term)));
+        Ok(ConceptResponse::Ok(Json(ConceptSynonyms {
+            id: id.0,
+            name,
+            synonyms,
+        })))
+    }
+
+    /// Explain an edge of the role graph: its score, the articles and
+    /// sentences supporting it and the years it was seen in
+    #[oai(
+        path = "/edges/:source/:target",
+        method = "get",
+        tag = "ApiTags::SearchQuery"
+    )]
+    async fn get_edge(
+        &self,
+        pool: Data<&Pool>,
+        roles: Data<&Roles>,
+        source: Path<String>,
+        target: Path<String>,
+        role: Query<Option<String>>,
+    ) -> ApiResult<EdgeResponse> {
+        let role = roles.resolve(role.0.as_deref())?;
+        let automata = roles.automata.get(role)?;
+        let mut con = pool.get().await?;
+        let scored: Vec<(String, f64)> = con
+            .zrevrange_withscores(format!("edges_scored:{}:{}", source.0, target.0), 0, -1)
+            .await?;
+        let edges = edges_between(&mut con, &role.graph_name, &source.0, &target.0).await?;
+        if scored.is_empty() && edges.is_empty() {
+            return Ok(EdgeResponse::NotFound);
+        }
+
+        let mut articles = Vec::new();
+        for (id, score) in scored.iter() {
+            let (title, url): (Option<String>, Option<String>) = con
+                .hget(format!("article:{}", id), &["title", "url"])
+                .await?;
+            // article was removed after its edges were scored
+            let title = match title {
+                Some(title) => title,
+                None => continue,
+            };
+            articles.push(SupportingArticle {
+                sentences: evidence_sentences(
+                    &mut con,
+                    &source.0,
+                    &target.0,
+                    id,
+                    EDGE_EVIDENCE_LIMIT,
+                )
+                .await?,
+                id: id.clone(),
+                title,
+                url: url.unwrap_or_default(),
+                score: *score,
+            });
+        }
+        let mut years: Vec<i64> = edges.iter().filter_map(|e| e.year).collect();
+        years.sort();
+        years.dedup();
+        let mut relations: Vec<String> = edges.iter().filter_map(|e| e.relation.clone()).collect();
+        relations.sort();
+        relations.dedup();
+        // the graph names articles by their title
+        let source_name = concept_name(&automata, &source.0)
+            .or_else(|| edges.iter().find_map(|e| e.e_name.clone()));
+        let target_name = concept_name(&automata, &target.0)
+            .or_else(|| edges.iter().find_map(|e| e.t_name.clone()));
+        Ok(EdgeResponse::Ok(Json(EdgeExplanation {
+            source_name,
+            target_name,
+            source: source.0,
+            target: target.0,
+            score: scored.iter().map(|(_, score)| score).sum(),
+            years,
+            relations,
+            articles,
+        })))
+    }
+
+    /// Reload every role's automata without restarting the server
+    #[oai(path = "/automata/reload", method = "post", tag = "ApiTags::Automata")]
+    async fn reload_automata(&self, roles: Data<&Roles>) -> Json<AutomataReload> {
+        let errors = tokio::task::block_in_place(|| roles.automata.reload(&roles.registry));
+        for err in errors.iter() {
+            println!("{}", err);
+        }
+        Json(AutomataReload {
+            loaded: roles.automata.roles(),
+            failed: errors.iter().map(ToString::to_string).collect(),
+        })
     }
 
-    //
-    // let links = get_edges(&nodes, Some(50), None, None).unwrap();
-    // println!("Links {:?}", links);
-    // let mut result_table = Vec::new();
-    // let mut article_set = std::collections::HashSet::new();
-    // let url = settings.redis_url.clone();
-    // let client = redis::Client::open(url).unwrap();
-    // let mut con = client.get_connection().unwrap();
-    // for each_record in links.iter().take(50) {
-    //     let edge_query = format!("{}:{}", each_record.source, each_record.target);
-    //     println!("Edge query {}", edge_query);
-    //     let edge_scored: Vec<String> = con.zrangebyscore_withscores(
-    //         format!("edges_scored:{}", edge_query),
-    //         "-inf",
-    //         "+inf",
-    //         (0, 5),
-    //     ).unwrap().into_iter().map(|(k, _)| k).collect();
-    //     println!("Edge scored {:?}", edge_scored);
-    //     for sentence_key in edge_scored {
-    //         let mut parts = sentence_key.split(':');
-    //         let article_id = parts.nth(1).unwrap();
-    //         if article_set.insert(article_id.to_owned()) {
-    //             let title: String = con.hget(format!("article_id:{}", article_id), "title").unwrap();
-    //             let hash_tag = parts.last().unwrap();
-    //             result_table.push(SearchResult {
-    //                 title,
-    //                 pk: hash_tag.to_owned(),
-    //                 url: "".to_owned(),
-    //             });
-    //         }
-    //     }
-    // }
-    // }
-
-    /// Find article by search term
+    /// Build the next version of the search index and swap it in once ready
+    #[oai(path = "/index/reindex", method = "post", tag = "ApiTags::SearchQuery")]
+    async fn reindex(&self, pool: Data<&Pool>) -> ApiResult<ReindexResponse> {
+        let lock = match search_index::lock_reindex(&pool).await? {
+            Some(lock) => lock,
+            None => return Ok(ReindexResponse::Conflict),
+        };
+        let pool: Pool = pool.0.clone();
+        tokio::spawn(async move {
+            if let Err(e) = search_index::reindex(&pool, lock).await {
+                println!("Reindex failed {:?}", e);
+            }
+        });
+        Ok(ReindexResponse::Accepted)
+    }
+
+    /// Find articles by search term, escaped unless `raw` is set, and filter.
+    /// Roles served from `/search` with haystack plugins search those first,
+    /// followed by the stored articles not found in them. The `total` of
+    /// such a page may count an article found in both twice. `filter`,
+    /// `raw`, `with_scores`, `highlight` and `summarize` need the article
+    /// index, queries using them search the index only.
     #[oai(path = "/search/", method = "post", tag = "ApiTags::SearchQuery")]
     async fn find_article(
         &self,
-        settings: Data<&Settings>,
+        pool: Data<&Pool>,
+        roles: Data<&Roles>,
         search_query: Json<SearchQuery>,
-    ) -> Json<Vec<RedisearchResult>> {
-        let url = settings.redis_url.clone();
-        let client = redis::Client::open(url).unwrap();
-        let mut con = client.get_connection().unwrap();
+    ) -> ApiResult<Json<SearchPage>> {
+        let role = roles.resolve(search_query.role.as_deref())?;
         println!("{:#?}", search_query);
+        let haystack_role =
+            role.server_url.trim_end_matches('/') == "/search" && !role.plugins.is_empty();
+        if haystack_role && !search_query.index_only() {
+            // plugins were checked at startup
+            let mut results = search_haystacks(
+                haystacks(role)?,
+                search_query.search_term.clone(),
+                roles.automata.get(role).ok(),
+            )
+            .await?;
+            let mut total = results.len();
+            let window = search_query.skip + search_query.limit;
+            // articles stored through `/articles` are only in the index
+            match index_search(&pool, &search_query, 0, window).await {
+                Ok((indexed, stored)) => {
+                    let found: HashSet<String> = results.iter().map(|r| r.id.clone()).collect();
+                    total += indexed;
+                    results.extend(stored.into_iter().filter(|r| !found.contains(&r.id)));
+                }
+                // haystack roles keep working without Redis
+                Err(e) if e.retryable() => println!("Searching haystacks only: {}", e),
+                Err(e) => return Err(e.into()),
+            }
+            return Ok(Json(SearchPage {
+                total,
+                skip: search_query.skip,
+                limit: search_query.limit,
+                results: results
+                    .into_iter()
+                    .skip(search_query.skip)
+                    .take(search_query.limit)
+                    .collect(),
+            }));
+        }
+        let (total, results) =
+            index_search(&pool, &search_query, search_query.skip, search_query.limit).await?;
+        Ok(Json(SearchPage {
+            total,
+            skip: search_query.skip,
+            limit: search_query.limit,
+            results,
+        }))
+    }
+}
 
-        let values: Vec<Value> = redis::cmd("FT.SEARCH")
-            .arg("ArticleIdx")
-            .arg(&search_query.search_term)
-            .arg("LIMIT")
-            .arg(search_query.skip)
-            .arg(search_query.limit)
-            .query(&mut con)
-            .unwrap();
-        println!("Output of scan");
-        println!("{:#?}", values);
-        let array_value = redis::Value::Bulk(values);
-        let results = parse_redisearch_response(&array_value);
-        Json(results)
+/// One page of the article index for `/search/`: the total hit count and
+/// the results from `skip`
+async fn index_search(
+    pool: &Pool,
+    search_query: &SearchQuery,
+    skip: usize,
+    limit: usize,
+) -> Result<(usize, Vec<RedisearchResult>), ApiError> {
+    let mut con = pool.get().await?;
+
+    let with_scores = search_query.with_scores.unwrap_or(false);
+    let highlight = search_query.highlight.unwrap_or(false);
+    let summarize = search_query.summarize.unwrap_or(false);
+    let query = search_query::compile(
+        &search_query.search_term,
+        search_query.raw.unwrap_or(false),
+        search_query.filter.as_ref(),
+    )?;
+    println!("Query {}", query.query);
+    let mut cmd = redis::cmd("FT.SEARCH");
+    cmd.arg(INDEX_ALIAS);
+    query.apply(&mut cmd);
+    if with_scores {
+        cmd.arg("WITHSCORES");
+    }
+    if summarize {
+        cmd.arg("SUMMARIZE").arg("FIELDS").arg(1).arg("body");
     }
+    if highlight {
+        cmd.arg("HIGHLIGHT")
+            .arg("FIELDS")
+            .arg(2)
+            .arg("title")
+            .arg("body");
+    }
+    cmd.arg("LIMIT").arg(skip).arg(limit);
+    let values: Value = cmd.query_async(&mut con).await?;
+    println!("Output of scan");
+    println!("{:#?}", values);
+    let layout = ReplyLayout { with_scores };
+    Ok(parse_redisearch_response(&values, &layout)?)
 }
 
 #[tokio::main]
@@ -346,6 +1074,36 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     tracing_subscriber::fmt::init();
     let settings = Settings::new().unwrap();
     println!("{:?}", settings);
+    let writes = EdgeWrites {
+        sharding: settings.sharding()?,
+        inline_graph_writes: settings.inline_graph_writes,
+    };
+    let roles = RoleRegistry::from_file(&settings.role_config)?;
+    println!("Roles {:?}", roles.shortnames());
+    for role in roles.iter() {
+        relevance_function(role)?;
+        fusion_method(role)?;
+        haystacks(role)?;
+    }
+    let automata = Arc::new(tokio::task::block_in_place(|| AutomataCache::load(&roles)));
+    println!("Automata loaded for {:?}", automata.roles());
+    let roles = Roles {
+        registry: roles,
+        automata,
+        default_role: settings.default_role.clone(),
+    };
+    let pool = settings.redis_pool()?;
+    match search_index::has_coordinator(&pool).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return Err("redis_mode is `cluster` but the cluster has no RediSearch coordinator, searches would only see one node".into())
+        }
+        Err(e) => println!("Search coordinator not checked: {}", e),
+    }
+    // haystack roles keep working without Redis
+    if let Err(e) = search_index::reconcile(&pool).await {
+        println!("Search index not reconciled: {}", e);
+    }
     let bind_addr = settings.server_url.clone();
     let api_endpoint = settings.api_endpoint.clone();
     let api_service = OpenApiService::new(Api, "Hello World", "1.0").server(api_endpoint);
@@ -356,7 +1114,10 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .nest("/doc", ui)
         .at("/spec", poem::endpoint::make_sync(move |_| spec.clone()))
         // .with(Cors::new())
-        .data(settings);
+        .data(settings)
+        .data(pool)
+        .data(roles)
+        .data(writes);
 
     Server::new(TcpListener::bind(bind_addr)).run(route).await?;
 
@@ -365,28 +1126,22 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
 #[cfg(test)]
 mod tests {
-    use tracing_subscriber::fmt::format;
-
     use super::*;
 
     #[test]
-    fn test_parse_article() {
-        
-        use serde_json;
-        use std::fs;
-        let input = fs::read_to_string("test-data/article.json").unwrap();
+    fn test_extract_edges() {
+        let input = std::fs::read_to_string("test-data/article.json").unwrap();
         let article: Article = serde_json::from_str(&input).unwrap();
-        let role = "project-manager";
-        let shard_id = 1;
-        let automata_url = "./test-data/term_to_id.json";
-        let expected_output = vec![Matched {
-            term: "project manager".to_string(),
-            id: "project-manager".to_string(),
-            nterm: "project management".to_string(),
-            pos: Some((0, 14)),
-        }];
-        let nodes = parse_article(&article, role, automata_url);
-
-        // assert_eq!(parse_article(&article,role, automata_url), expected_output);
+        let automata = terraphim_automata::load_automata("./test-data/term_to_id.json").unwrap();
+        let extraction = ingest::extract_edges(&article.body, &automata, 2023).unwrap();
+        assert!(!extraction.edges.is_empty());
+        for edge in extraction.edges.iter() {
+            assert_eq!(edge.year, 2023);
+            assert!(automata.values().any(|concept| concept.id == edge.source));
+            assert!(automata
+                .values()
+                .any(|concept| concept.id == edge.destination));
+        }
+        assert!(!extraction.synonyms.is_empty());
     }
 }
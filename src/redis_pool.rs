@@ -0,0 +1,195 @@
+//! Redis connections that work the same against a standalone server and a
+//! Redis Cluster. Handlers take a `Connection` from the `Pool` and use it
+//! like any async redis connection.
+//!
+//! RediSearch commands (`FT.*`) carry no key, so on a cluster they reach a
+//! single node. They only see the whole keyspace when the cluster runs the
+//! RediSearch coordinator, see `search_index::has_coordinator`.
+use std::sync::Arc;
+use std::time::Duration;
+
+use deadpool_redis::PoolError;
+use redis::aio::ConnectionLike;
+use redis::cluster::ClusterClient;
+use redis::cluster_async::ClusterConnection;
+use redis::{Cmd, ErrorKind, Pipeline, RedisError, RedisFuture, Value};
+use thiserror::Error;
+use tokio::sync::OnceCell;
+
+#[derive(Debug, Error)]
+pub enum CreatePoolError {
+    #[error(transparent)]
+    Standalone(#[from] deadpool_redis::CreatePoolError),
+    #[error("invalid cluster nodes: {0}")]
+    Cluster(#[from] RedisError),
+    #[error("{0} does not apply to a cluster, which shares one multiplexed connection")]
+    ClusterSetting(&'static str),
+    #[error("unknown redis_mode `{0}`, use `standalone` or `cluster`")]
+    Mode(String),
+    #[error("redis_mode is `cluster` but redis_cluster_url names no nodes")]
+    NoClusterNodes,
+}
+
+/// Connections to a standalone server, or to the nodes of a cluster
+#[derive(Clone)]
+pub enum Pool {
+    Standalone(deadpool_redis::Pool),
+    Cluster(ClusterPool),
+}
+
+impl Pool {
+    pub async fn get(&self) -> Result<Connection, PoolError> {
+        match self {
+            Pool::Standalone(pool) => Ok(Connection::Standalone(pool.get().await?)),
+            Pool::Cluster(pool) => Ok(Connection::Cluster(pool.get().await?)),
+        }
+    }
+}
+
+/// A cluster connection is multiplexed and keeps a connection per node,
+/// so one is shared by every handler. It's opened on first use, like the
+/// connections of the standalone pool.
+#[derive(Clone)]
+pub struct ClusterPool {
+    client: ClusterClient,
+    connect_timeout: Duration,
+    connection: Arc<OnceCell<ClusterConnection>>,
+}
+
+impl ClusterPool {
+    /// `nodes` are the urls of some of the cluster nodes, the others are
+    /// discovered. Opening the connection gives up after `connect_timeout`.
+    pub fn new(nodes: Vec<String>, connect_timeout: Duration) -> Result<Self, CreatePoolError> {
+        Ok(ClusterPool {
+            client: ClusterClient::new(nodes)?,
+            connect_timeout,
+            connection: Arc::new(OnceCell::new()),
+        })
+    }
+
+    async fn get(&self) -> Result<ClusterConnection, PoolError> {
+        let connect = || async {
+            tokio::time::timeout(self.connect_timeout, self.client.get_async_connection())
+                .await
+                .unwrap_or_else(|_| {
+                    Err(RedisError::from((
+                        ErrorKind::IoError,
+                        "timed out connecting to the cluster",
+                    )))
+                })
+        };
+        let connection = self
+            .connection
+            .get_or_try_init(connect)
+            .await
+            .map_err(PoolError::Backend)?;
+        Ok(connection.clone())
+    }
+}
+
+pub enum Connection {
+    Standalone(deadpool_redis::Connection),
+    Cluster(ClusterConnection),
+}
+
+/// Replies of a pipeline run command by command, shaped as
+/// `req_packed_commands` returns them: a transaction is answered by its
+/// `EXEC` alone, which redis-rs asks for with an `offset` past the queued
+/// commands.
+fn pipeline_replies(replies: Vec<Value>, offset: usize) -> Vec<Value> {
+    if offset > 0 {
+        vec![Value::Bulk(replies)]
+    } else {
+        replies
+    }
+}
+
+impl ConnectionLike for Connection {
+    fn req_packed_command<'a>(&'a mut self, cmd: &'a Cmd) -> RedisFuture<'a, Value> {
+        match self {
+            Connection::Standalone(con) => con.req_packed_command(cmd),
+            Connection::Cluster(con) => con.req_packed_command(cmd),
+        }
+    }
+
+    /// A cluster sends a whole pipeline to the node of its first key, so
+    /// pipelines spanning slots are run command by command there, each
+    /// routed to the node of its key. `MULTI` blocks are not atomic on a
+    /// cluster.
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        pipeline: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        match self {
+            Connection::Standalone(con) => con.req_packed_commands(pipeline, offset, count),
+            Connection::Cluster(con) => Box::pin(async move {
+                let mut replies = Vec::new();
+                for cmd in pipeline.cmd_iter() {
+                    replies.push(con.req_packed_command(cmd).await?);
+                }
+                Ok(pipeline_replies(replies, offset))
+            }),
+        }
+    }
+
+    fn get_db(&self) -> i64 {
+        match self {
+            Connection::Standalone(con) => con.get_db(),
+            Connection::Cluster(con) => con.get_db(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redis::AsyncCommands;
+
+    #[test]
+    fn test_pipeline_replies() {
+        let replies = vec![Value::Int(1), Value::Okay];
+        assert_eq!(pipeline_replies(replies.clone(), 0), replies);
+        assert_eq!(
+            pipeline_replies(replies.clone(), 3),
+            vec![Value::Bulk(replies)]
+        );
+    }
+
+    /// Needs a local cluster, e.g. `utils/create-cluster/create-cluster start`
+    /// and `create` from the Redis sources, listening on ports 30001 to 30006:
+    /// `cargo test -- --ignored test_cluster_pipelines`
+    #[tokio::test]
+    #[ignore]
+    async fn test_cluster_pipelines() {
+        let nodes = std::env::var("TERRAPHIM_REDIS_CLUSTER_URL")
+            .unwrap_or_else(|_| "redis://127.0.0.1:30001,redis://127.0.0.1:30002".to_string());
+        let pool = Pool::Cluster(
+            ClusterPool::new(
+                nodes.split(',').map(str::to_string).collect(),
+                Duration::from_secs(1),
+            )
+            .unwrap(),
+        );
+        let mut con = pool.get().await.unwrap();
+        // keys in different slots
+        let keys: Vec<String> = (0..16).map(|i| format!("cluster_test:{i}")).collect();
+        let mut pipe = redis::pipe();
+        for key in keys.iter() {
+            pipe.set(key, 1).ignore().incr(key, 1);
+        }
+        let counts: Vec<i64> = pipe.query_async(&mut con).await.unwrap();
+        assert_eq!(counts, vec![2; 16]);
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for key in keys.iter() {
+            pipe.del(key);
+        }
+        let deleted: Vec<i64> = pipe.query_async(&mut con).await.unwrap();
+        assert_eq!(deleted, vec![1; 16]);
+        let exists: bool = con.exists(&keys[0]).await.unwrap();
+        assert!(!exists);
+    }
+}
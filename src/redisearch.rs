@@ -0,0 +1,161 @@
+use poem_openapi::Object;
+use redis::{from_redis_value, ErrorKind, FromRedisValue, RedisError, RedisResult, Value};
+use serde::{Deserialize, Serialize};
+
+fn create_error(msg: &'static str) -> RedisError {
+    (ErrorKind::TypeError, msg).into()
+}
+
+/// A document returned by FT.SEARCH.
+#[derive(Object, Serialize, Deserialize, Debug, Default, Clone)]
+pub struct RedisearchResult {
+    /// Article id, without the `article:` key prefix
+    pub id: String,
+    pub stub: Option<String>,
+    /// With `highlight` the matched terms are wrapped in `<b>` tags
+    pub title: String,
+    pub url: String,
+    /// With `highlight` the matched terms are wrapped in `<b>` tags, with
+    /// `summarize` only the fragments around them are returned
+    pub body: String,
+    pub description: Option<String>,
+    pub tags: Option<Vec<String>>,
+    /// Relevance score, set when the search asked for scores
+    pub score: Option<f64>,
+}
+
+/// Parses the field/value list of a single document. The id is not part of
+/// it and has to be set by the caller.
+impl FromRedisValue for RedisearchResult {
+    fn from_redis_value(v: &Value) -> RedisResult<Self> {
+        let values: Vec<Option<String>> = from_redis_value(v)?;
+        let mut result = RedisearchResult::default();
+        for pair in values.chunks(2) {
+            let (key, value) = match pair {
+                [Some(key), Some(value)] => (key.as_str(), value.clone()),
+                _ => continue,
+            };
+            match key {
+                "id" => result.id = value,
+                "title" => result.title = value,
+                "stub" => result.stub = Some(value),
+                "url" => result.url = value,
+                "body" => result.body = value,
+                "description" => result.description = Some(value),
+                "tags" => {
+                    result.tags = Some(
+                        value
+                            .split(',')
+                            .map(|s| s.trim().to_string())
+                            .filter(|s| !s.is_empty())
+                            .collect(),
+                    )
+                }
+                _ => continue,
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// How the FT.SEARCH reply is laid out, mirrors the options of the query.
+#[derive(Debug, Default, Clone)]
+pub struct ReplyLayout {
+    /// WITHSCORES: a score follows every document id
+    pub with_scores: bool,
+}
+
+/// One page of FT.SEARCH results
+#[derive(Object, Debug, Default)]
+pub struct SearchPage {
+    /// Number of matching documents, not just the ones on this page
+    pub total: usize,
+    pub skip: usize,
+    pub limit: usize,
+    pub results: Vec<RedisearchResult>,
+}
+
+/// Parses a FT.SEARCH reply: `[total, id, (score,) [field, value, ...], ...]`.
+/// Returns the total hit count and the documents of the page.
+pub fn parse_redisearch_response(
+    response: &Value,
+    layout: &ReplyLayout,
+) -> RedisResult<(usize, Vec<RedisearchResult>)> {
+    let items = match response {
+        Value::Bulk(items) => items,
+        _ => return Err(create_error("FT.SEARCH reply is not an array")),
+    };
+    let mut items = items.iter();
+    let total: usize = match items.next() {
+        Some(total) => from_redis_value(total)?,
+        None => return Err(create_error("FT.SEARCH reply misses the total")),
+    };
+    let mut results = Vec::new();
+    while let Some(id) = items.next() {
+        let id: String = from_redis_value(id)?;
+        let score = if layout.with_scores {
+            match items.next() {
+                Some(score) => Some(from_redis_value::<f64>(score)?),
+                None => return Err(create_error("FT.SEARCH reply misses a score")),
+            }
+        } else {
+            None
+        };
+        let mut result = match items.next() {
+            Some(fields) => RedisearchResult::from_redis_value(fields)?,
+            None => return Err(create_error("FT.SEARCH reply misses document fields")),
+        };
+        result.id = id.strip_prefix("article:").unwrap_or(&id).to_string();
+        result.score = score;
+        results.push(result);
+    }
+    Ok((total, results))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_recorded_reply_withscores() {
+        let reply = std::fs::read("test-data/ft_search_withscores.resp").unwrap();
+        let value = redis::parse_redis_value(&reply).unwrap();
+        let layout = ReplyLayout { with_scores: true };
+        let (total, results) = parse_redisearch_response(&value, &layout).unwrap();
+        assert_eq!(total, 7);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].id, "01H6VGEFEAVH6ZN4G5TGZZ21RA");
+        assert_eq!(results[0].score, Some(1.5));
+        assert_eq!(
+            results[0].tags,
+            Some(vec!["security".to_string(), "ml".to_string()])
+        );
+        assert_eq!(
+            results[0].title,
+            "Attacking <b>Machine</b> Learning Systems"
+        );
+        assert_eq!(results[1].description, None);
+    }
+
+    #[test]
+    fn test_parse_truncated_reply() {
+        // odd field list and a missing document must not panic
+        let value = Value::Bulk(vec![
+            Value::Int(2),
+            Value::Data(b"article:1".to_vec()),
+            Value::Bulk(vec![
+                Value::Data(b"title".to_vec()),
+                Value::Data(b"First".to_vec()),
+                Value::Data(b"body".to_vec()),
+            ]),
+            Value::Data(b"article:2".to_vec()),
+        ]);
+        let layout = ReplyLayout::default();
+        assert!(parse_redisearch_response(&value, &layout).is_err());
+
+        let value = Value::Bulk(vec![Value::Int(0)]);
+        let (total, results) = parse_redisearch_response(&value, &layout).unwrap();
+        assert_eq!(total, 0);
+        assert!(results.is_empty());
+    }
+}
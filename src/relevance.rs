@@ -0,0 +1,228 @@
+use std::collections::HashSet;
+
+use async_trait::async_trait;
+use redis::{AsyncCommands, Value};
+use terraphim_automata::find_matches;
+
+use crate::automata::Automata;
+use crate::error::ApiError;
+use crate::graph_search::{article_support, get_edges, matched_concepts, quoted, Edge, EdgeFilter};
+use crate::redis_pool::Connection;
+use crate::redisearch::{parse_redisearch_response, ReplyLayout};
+use crate::roles::{Role, RoleError};
+use crate::search_index::INDEX_ALIAS;
+use crate::search_query;
+
+/// Edges of the role graph considered per search
+const EDGES_LIMIT: i64 = 50;
+
+/// What a relevance function gets to rank articles for one search
+pub struct RankContext<'a> {
+    pub con: &'a mut Connection,
+    pub role: &'a Role,
+    pub automata: &'a Automata,
+    pub search_term: &'a str,
+    /// Most articles the ranking needs to hold
+    pub limit: usize,
+    /// Relationship types graph based rankings follow, all when `None`
+    pub relations: Option<&'a [String]>,
+    /// Years of the edges graph based rankings follow, all when `None`
+    pub years: Option<&'a [i64]>,
+}
+
+impl<'a> RankContext<'a> {
+    fn edge_filter(&self) -> EdgeFilter<'a> {
+        EdgeFilter {
+            years: self.years,
+            relations: self.relations,
+        }
+    }
+}
+
+/// Article ranked by a relevance function
+#[derive(Debug, Clone)]
+pub struct Ranked {
+    pub id: String,
+    pub score: f64,
+    /// Graph edges the article was found through, empty for text rankings
+    pub edges: Vec<Edge>,
+}
+
+impl Ranked {
+    fn new(id: String, score: f64) -> Self {
+        Ranked {
+            id,
+            score,
+            edges: Vec::new(),
+        }
+    }
+}
+
+/// Ranks the corpus for a search, selected per role by `relevance_function`
+/// so different roles can order the same articles differently.
+#[async_trait]
+pub trait RelevanceFunction: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    /// Articles with their score, best first
+    async fn rank(&self, ctx: &mut RankContext<'_>) -> Result<Vec<Ranked>, ApiError>;
+}
+
+/// Looks up the relevance function named in the role config. `rust` is the
+/// name older configs use for the graph edge rank.
+pub fn relevance_function(role: &Role) -> Result<Box<dyn RelevanceFunction>, RoleError> {
+    match role.relevance_function.as_str() {
+        "graph-edge-rank" | "rust" => Ok(Box::new(GraphEdgeRank)),
+        "bm25" => Ok(Box::new(Bm25)),
+        "term-frequency" => Ok(Box::new(TermFrequency)),
+        name => Err(RoleError::UnknownRelevance {
+            role: role.shortname.clone(),
+            name: name.to_string(),
+        }),
+    }
+}
+
+fn sorted(mut ranked: Vec<Ranked>, limit: usize) -> Vec<Ranked> {
+    ranked.sort_by(|a, b| b.score.total_cmp(&a.score).then_with(|| a.id.cmp(&b.id)));
+    ranked.truncate(limit);
+    ranked
+}
+
+/// Sums the scores of the articles supporting the strongest graph edges
+/// leaving the concepts of the search term.
+pub struct GraphEdgeRank;
+
+#[async_trait]
+impl RelevanceFunction for GraphEdgeRank {
+    fn name(&self) -> &'static str {
+        "graph-edge-rank"
+    }
+
+    async fn rank(&self, ctx: &mut RankContext<'_>) -> Result<Vec<Ranked>, ApiError> {
+        let nodes = quoted(&matched_concepts(ctx.search_term, ctx.automata)?);
+        let filter = ctx.edge_filter();
+        let edges = get_edges(ctx.con, &ctx.role.graph_name, &nodes, filter, EDGES_LIMIT).await?;
+        let support = article_support(ctx.con, &edges).await?;
+        let ranked = support
+            .into_iter()
+            .map(|(id, article)| Ranked {
+                id,
+                score: article.score,
+                edges: article.supporting(&edges),
+            })
+            .collect();
+        Ok(sorted(ranked, ctx.limit))
+    }
+}
+
+/// BM25 full-text ranking of the search term by RediSearch
+pub struct Bm25;
+
+#[async_trait]
+impl RelevanceFunction for Bm25 {
+    fn name(&self) -> &'static str {
+        "bm25"
+    }
+
+    async fn rank(&self, ctx: &mut RankContext<'_>) -> Result<Vec<Ranked>, ApiError> {
+        let query = search_query::compile(ctx.search_term, false, None)?;
+        let mut cmd = redis::cmd("FT.SEARCH");
+        cmd.arg(INDEX_ALIAS);
+        query.apply(&mut cmd);
+        cmd.arg("SCORER")
+            .arg("BM25")
+            .arg("WITHSCORES")
+            .arg("RETURN")
+            .arg(1)
+            .arg("title")
+            .arg("LIMIT")
+            .arg(0)
+            .arg(ctx.limit);
+        let values: Value = cmd.query_async(ctx.con).await?;
+        let layout = ReplyLayout { with_scores: true };
+        let (_, results) = parse_redisearch_response(&values, &layout)?;
+        Ok(results
+            .into_iter()
+            .map(|r| Ranked::new(r.id, r.score.unwrap_or_default()))
+            .collect())
+    }
+}
+
+/// Counts how often the concepts of the search term occur in the body of
+/// the articles linked to them in the role graph.
+pub struct TermFrequency;
+
+#[async_trait]
+impl RelevanceFunction for TermFrequency {
+    fn name(&self) -> &'static str {
+        "term-frequency"
+    }
+
+    async fn rank(&self, ctx: &mut RankContext<'_>) -> Result<Vec<Ranked>, ApiError> {
+        let concepts = matched_concepts(ctx.search_term, ctx.automata)?;
+        let filter = ctx.edge_filter();
+        let edges = get_edges(
+            ctx.con,
+            &ctx.role.graph_name,
+            &quoted(&concepts),
+            filter,
+            EDGES_LIMIT,
+        )
+        .await?;
+        let support = article_support(ctx.con, &edges).await?;
+        let mut ranked = Vec::new();
+        for (id, article) in support {
+            let body: Option<String> = ctx.con.hget(format!("article:{}", id), "body").await?;
+            // article was removed after its edges were scored
+            let body = match body {
+                Some(body) => body,
+                None => continue,
+            };
+            let count = concept_frequency(&body, &concepts, ctx.automata)?;
+            if count > 0 {
+                ranked.push(Ranked {
+                    id,
+                    score: count as f64,
+                    edges: article.supporting(&edges),
+                });
+            }
+        }
+        Ok(sorted(ranked, ctx.limit))
+    }
+}
+
+/// Occurrences of `concepts` in `text`
+fn concept_frequency(
+    text: &str,
+    concepts: &HashSet<String>,
+    automata: &Automata,
+) -> Result<usize, ApiError> {
+    let matched = find_matches(text, automata.clone(), false)
+        .map_err(|e| ApiError::Matching(e.to_string()))?;
+    Ok(matched.iter().filter(|m| concepts.contains(&m.id)).count())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roles::RoleRegistry;
+
+    #[test]
+    fn test_relevance_function_by_role() {
+        let roles = RoleRegistry::from_json(
+            r#"{"roles": {
+                "Engineer": {"name": "Engineer", "relevance_function": "rust", "serverUrl": ""},
+                "Analyst": {"name": "Analyst", "relevance_function": "bm25", "serverUrl": ""},
+                "Writer": {"name": "Writer", "relevance_function": "tf-idf", "serverUrl": ""}
+            }}"#,
+        )
+        .unwrap();
+        let name = |role| relevance_function(roles.get(role).unwrap()).map(|f| f.name());
+        assert_eq!(name("engineer").unwrap(), "graph-edge-rank");
+        assert_eq!(name("analyst").unwrap(), "bm25");
+        assert!(matches!(
+            name("writer"),
+            Err(RoleError::UnknownRelevance { .. })
+        ));
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::Deserialize;
+use thiserror::Error;
+
+/// Errors raised while loading or resolving roles.
+#[derive(Debug, Error)]
+pub enum RoleError {
+    #[error("failed to read role config {path}: {source}")]
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("failed to parse role config {path}: {source}")]
+    Parse {
+        path: String,
+        source: serde_json::Error,
+    },
+    #[error("unknown role `{role}`, valid roles are: {}", valid.join(", "))]
+    Unknown { role: String, valid: Vec<String> },
+    #[error("role `{role}` has no automata_url configured")]
+    NoAutomata { role: String },
+    #[error("role `{role}` uses unknown relevance function `{name}`")]
+    UnknownRelevance { role: String, name: String },
+    #[error("role `{role}` uses unknown fusion method `{name}`")]
+    UnknownFusion { role: String, name: String },
+    #[error("role `{role}` uses unknown plugin `{name}`")]
+    UnknownPlugin { role: String, name: String },
+}
+
+/// A plugin of a role, e.g. `terraphim-grep` searching a notes directory.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Plugin {
+    pub name: String,
+    /// Directory the plugin searches, `~` is the home directory
+    #[serde(rename = "hackstack", default)]
+    pub haystack: Option<String>,
+}
+
+/// A single role as declared in `desktop_config.json`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Role {
+    /// Short, url friendly name, derived from `name` when missing in the config
+    #[serde(default)]
+    pub shortname: String,
+    pub name: String,
+    /// Ranking used by `/rsearch/`: `graph-edge-rank` (alias `rust`),
+    /// `bm25` or `term-frequency`
+    pub relevance_function: String,
+    /// How `/hsearch/` fuses full-text and graph rankings: `rrf`, `rrf:{k}`,
+    /// `weighted_sum` or `weighted_sum:{text_weight}`. `rrf` when missing.
+    #[serde(default)]
+    pub fusion: Option<String>,
+    #[serde(rename = "serverUrl")]
+    pub server_url: String,
+    pub automata_url: Option<String>,
+    /// Local automata file loaded instead of `automata_url`, e.g. to work
+    /// offline or pin a thesaurus while the remote one changes
+    #[serde(default)]
+    pub automata_path: Option<String>,
+    /// RedisGraph key holding the role's knowledge graph
+    #[serde(default)]
+    pub graph_name: String,
+    #[serde(default)]
+    pub plugins: Vec<Plugin>,
+}
+
+impl Role {
+    /// Haystack directories of the role's plugins
+    pub fn haystacks(&self) -> Vec<&str> {
+        let mut haystacks: Vec<&str> = self
+            .plugins
+            .iter()
+            .filter_map(|p| p.haystack.as_deref())
+            .filter(|h| !h.is_empty())
+            .collect();
+        haystacks.dedup();
+        haystacks
+    }
+
+    /// Where the role's automata is loaded from, `automata_path` when set
+    pub fn automata_url(&self) -> Result<&str, RoleError> {
+        self.automata_path
+            .as_deref()
+            .filter(|path| !path.is_empty())
+            .or_else(|| self.automata_url.as_deref().filter(|url| !url.is_empty()))
+            .ok_or_else(|| RoleError::NoAutomata {
+                role: self.shortname.clone(),
+            })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct DesktopConfig {
+    roles: BTreeMap<String, Role>,
+}
+
+/// Roles keyed by shortname, shared with handlers through poem `Data`.
+#[derive(Debug, Clone, Default)]
+pub struct RoleRegistry {
+    roles: BTreeMap<String, Role>,
+}
+
+impl RoleRegistry {
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self, RoleError> {
+        let path = path.as_ref();
+        let input = std::fs::read_to_string(path).map_err(|source| RoleError::Io {
+            path: path.display().to_string(),
+            source,
+        })?;
+        Self::from_json(&input).map_err(|source| RoleError::Parse {
+            path: path.display().to_string(),
+            source,
+        })
+    }
+
+    pub fn from_json(input: &str) -> Result<Self, serde_json::Error> {
+        let config: DesktopConfig = serde_json::from_str(input)?;
+        let roles = config
+            .roles
+            .into_values()
+            .map(|mut role| {
+                if role.shortname.is_empty() {
+                    role.shortname = role.name.to_lowercase().replace(' ', "-");
+                }
+                if role.graph_name.is_empty() {
+                    role.graph_name = format!("graph_{}", role.shortname);
+                }
+                (role.shortname.clone(), role)
+            })
+            .collect();
+        Ok(RoleRegistry { roles })
+    }
+
+    /// Resolves a role by shortname or display name, case insensitive.
+    pub fn get(&self, role: &str) -> Result<&Role, RoleError> {
+        let wanted = role.trim().to_lowercase();
+        self.roles
+            .get(&wanted)
+            .or_else(|| {
+                self.roles
+                    .values()
+                    .find(|r| r.name.to_lowercase() == wanted)
+            })
+            .ok_or_else(|| RoleError::Unknown {
+                role: role.to_string(),
+                valid: self.shortnames(),
+            })
+    }
+
+    pub fn shortnames(&self) -> Vec<String> {
+        self.roles.keys().cloned().collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Role> {
+        self.roles.values()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_roles() {
+        let registry = RoleRegistry::from_file("config/desktop_config.json").unwrap();
+        let role = registry.get("project-manager").unwrap();
+        assert_eq!(role.name, "Project Manager");
+        assert_eq!(
+            registry.get("Project Manager").unwrap().shortname,
+            "project-manager"
+        );
+        // roles without shortname get one derived from their name
+        assert_eq!(registry.get("father").unwrap().name, "Father");
+        assert!(registry.get("default").unwrap().automata_url().is_err());
+        // the local thesaurus wins over the published one
+        assert_eq!(role.automata_url().unwrap(), "./test-data/term_to_id.json");
+        assert!(registry
+            .get("lazy-project-manager")
+            .unwrap()
+            .automata_url()
+            .unwrap()
+            .starts_with("https://"));
+        assert_eq!(
+            registry.get("default").unwrap().haystacks(),
+            vec!["~/obsidian", "~/obsidian/World"]
+        );
+    }
+
+    #[test]
+    fn test_unknown_role() {
+        let registry = RoleRegistry::from_file("config/desktop_config.json").unwrap();
+        match registry.get("astronaut") {
+            Err(RoleError::Unknown { valid, .. }) => {
+                assert!(valid.contains(&"operator".to_string()))
+            }
+            other => panic!("expected unknown role error, got {:?}", other),
+        }
+    }
+}
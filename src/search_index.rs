@@ -0,0 +1,449 @@
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+
+use redis::{from_redis_value, RedisResult, Script, Value};
+use ulid::Ulid;
+
+use crate::redis_pool::{Connection, Pool};
+
+/// Name queries use. It is an alias of the versioned index `ArticleIdx_v{n}`,
+/// so a reindex can build the next version and swap the alias when done.
+pub const INDEX_ALIAS: &str = "ArticleIdx";
+
+/// Held while a reindex runs, so two of them can't build the same version
+pub const REINDEX_LOCK: &str = "lock:reindex";
+/// Expiry of the lock, renewed while the new index catches up, so a crashed
+/// reindex doesn't block the next one for long
+const REINDEX_LOCK_TTL: Duration = Duration::from_secs(60);
+
+/// Field of the article index: name, type and extra FT.CREATE options
+pub struct SchemaField {
+    pub name: &'static str,
+    pub kind: &'static str,
+    pub options: &'static [&'static str],
+}
+
+pub const ARTICLE_SCHEMA: &[SchemaField] = &[
+    SchemaField {
+        name: "title",
+        kind: "TEXT",
+        options: &["WEIGHT", "5.0"],
+    },
+    SchemaField {
+        name: "body",
+        kind: "TEXT",
+        options: &[],
+    },
+    SchemaField {
+        name: "url",
+        kind: "TEXT",
+        options: &[],
+    },
+    SchemaField {
+        name: "description",
+        kind: "TEXT",
+        options: &[],
+    },
+    SchemaField {
+        name: "tags",
+        kind: "TAG",
+        options: &["SEPARATOR", ","],
+    },
+    SchemaField {
+        name: "created",
+        kind: "NUMERIC",
+        options: &["SORTABLE"],
+    },
+];
+
+pub fn schema_field(name: &str) -> Option<&'static SchemaField> {
+    ARTICLE_SCHEMA.iter().find(|f| f.name == name)
+}
+
+/// The parts of FT.INFO used to reconcile the index
+#[derive(Debug, Default, PartialEq)]
+pub struct IndexInfo {
+    /// Real index name, FT.INFO resolves aliases
+    pub name: String,
+    /// Field name, type and the options compared with the schema
+    pub fields: BTreeSet<(String, String, Vec<String>)>,
+    /// Whether the initial scan of existing hashes is still running
+    pub indexing: bool,
+}
+
+impl IndexInfo {
+    pub fn from_value(value: &Value) -> RedisResult<Self> {
+        let pairs: Vec<Value> = from_redis_value(value)?;
+        let mut info: HashMap<String, Value> = HashMap::new();
+        for pair in pairs.chunks(2) {
+            if let [key, value] = pair {
+                info.insert(from_redis_value(key)?, value.clone());
+            }
+        }
+        let name = match info.get("index_name") {
+            Some(v) => from_redis_value(v)?,
+            None => String::new(),
+        };
+        // an integer, older versions reply with a string
+        let indexing = match info.get("indexing") {
+            Some(Value::Int(n)) => *n != 0,
+            Some(v) => from_redis_value::<String>(v)? != "0",
+            None => false,
+        };
+        // RediSearch 2.0 calls them `fields`, later versions `attributes`
+        let attributes = info.get("attributes").or_else(|| info.get("fields"));
+        let mut fields = BTreeSet::new();
+        if let Some(attributes) = attributes {
+            let attributes: Vec<Vec<String>> = from_redis_value(attributes)?;
+            for attribute in attributes {
+                if let Some(field) = field_from_attribute(&attribute) {
+                    fields.insert(field);
+                }
+            }
+        }
+        Ok(IndexInfo {
+            name,
+            fields,
+            indexing,
+        })
+    }
+
+    /// True when the index doesn't have exactly the fields of `ARTICLE_SCHEMA`,
+    /// with the same weights, separators and sortability
+    pub fn has_drift(&self) -> bool {
+        self.fields != expected_fields()
+    }
+
+    /// Version of a `ArticleIdx_v{n}` index, 0 for the unversioned legacy index
+    pub fn version(&self) -> u32 {
+        self.name
+            .strip_prefix(INDEX_ALIAS)
+            .and_then(|v| v.strip_prefix("_v"))
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0)
+    }
+}
+
+fn value_of<'a, S: AsRef<str>>(list: &'a [S], key: &str) -> Option<&'a str> {
+    list.iter()
+        .position(|a| a.as_ref().eq_ignore_ascii_case(key))
+        .and_then(|i| list.get(i + 1))
+        .map(|v| v.as_ref())
+}
+
+/// Options of a field as FT.INFO shows them, with RediSearch's defaults
+/// filled in: a TEXT weight of 1 and a TAG separator of `,`
+fn field_options<S: AsRef<str>>(kind: &str, options: &[S]) -> Vec<String> {
+    let mut normalised = Vec::new();
+    match kind {
+        "TEXT" => {
+            let weight = value_of(options, "WEIGHT")
+                .and_then(|w| w.parse::<f64>().ok())
+                .unwrap_or(1.0);
+            normalised.extend(["WEIGHT".to_string(), weight.to_string()]);
+        }
+        "TAG" => {
+            let separator = value_of(options, "SEPARATOR").unwrap_or(",");
+            normalised.extend(["SEPARATOR".to_string(), separator.to_string()]);
+        }
+        _ => {}
+    }
+    if options
+        .iter()
+        .any(|o| o.as_ref().eq_ignore_ascii_case("SORTABLE"))
+    {
+        normalised.push("SORTABLE".to_string());
+    }
+    normalised
+}
+
+fn field_from_attribute(attribute: &[String]) -> Option<(String, String, Vec<String>)> {
+    // ["identifier", "title", "attribute", "title", "type", "TEXT", "WEIGHT", "5", ...]
+    // or on 2.0: ["title", "type", "TEXT", ...]
+    let name =
+        value_of(attribute, "attribute").or_else(|| attribute.first().map(|a| a.as_str()))?;
+    let kind = value_of(attribute, "type")?.to_uppercase();
+    let options = field_options(&kind, attribute);
+    Some((name.to_string(), kind, options))
+}
+
+fn expected_fields() -> BTreeSet<(String, String, Vec<String>)> {
+    ARTICLE_SCHEMA
+        .iter()
+        .map(|f| {
+            (
+                f.name.to_string(),
+                f.kind.to_string(),
+                field_options(f.kind, f.options),
+            )
+        })
+        .collect()
+}
+
+pub fn index_name(version: u32) -> String {
+    format!("{INDEX_ALIAS}_v{version}")
+}
+
+pub fn create_index_cmd(index: &str) -> redis::Cmd {
+    let mut cmd = redis::cmd("FT.CREATE");
+    cmd.arg(index)
+        .arg("ON")
+        .arg("HASH")
+        .arg("PREFIX")
+        .arg(1)
+        .arg("article:")
+        .arg("SCHEMA");
+    for field in ARTICLE_SCHEMA {
+        cmd.arg(field.name).arg(field.kind).arg(field.options);
+    }
+    cmd
+}
+
+/// FT.INFO of the index behind the alias, `None` when there is none.
+pub async fn index_info(con: &mut Connection) -> RedisResult<Option<IndexInfo>> {
+    match redis::cmd("FT.INFO")
+        .arg(INDEX_ALIAS)
+        .query_async::<_, Value>(con)
+        .await
+    {
+        Ok(value) => Ok(Some(IndexInfo::from_value(&value)?)),
+        Err(e) if is_missing_index(&e) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn is_missing_index(err: &redis::RedisError) -> bool {
+    let message = format!(
+        "{} {}",
+        err.code().unwrap_or_default(),
+        err.detail().unwrap_or_default()
+    )
+    .to_lowercase();
+    message.contains("unknown index name") || message.contains("no such index")
+}
+
+/// Whether FT.* commands see the whole keyspace. True on a standalone
+/// server. On a cluster an FT.* command is served by whichever node gets
+/// it, so the nodes need the RediSearch coordinator, which adds
+/// `SEARCH.CLUSTERINFO`; without it each node only indexes its own slots.
+pub async fn has_coordinator(pool: &Pool) -> Result<bool, crate::error::ApiError> {
+    if let Pool::Standalone(_) = pool {
+        return Ok(true);
+    }
+    let mut con = pool.get().await?;
+    let info: Vec<Value> = redis::cmd("COMMAND")
+        .arg("INFO")
+        .arg("SEARCH.CLUSTERINFO")
+        .query_async(&mut con)
+        .await?;
+    Ok(info.iter().any(|command| *command != Value::Nil))
+}
+
+/// Makes sure `ArticleIdx` serves the current schema. A missing index is
+/// created right away. On drift the next version is built in the background
+/// while the old one keeps serving queries, see `reindex`.
+pub async fn reconcile(pool: &Pool) -> Result<(), crate::error::ApiError> {
+    let mut con = pool.get().await?;
+    match index_info(&mut con).await? {
+        None => {
+            let index = index_name(1);
+            println!("Creating search index {}", index);
+            create_index_cmd(&index)
+                .query_async::<_, ()>(&mut con)
+                .await?;
+            redis::cmd("FT.ALIASADD")
+                .arg(INDEX_ALIAS)
+                .arg(&index)
+                .query_async::<_, ()>(&mut con)
+                .await?;
+        }
+        Some(info) if info.has_drift() => {
+            println!(
+                "Search index {} drifted from the schema: {:?}",
+                info.name, info.fields
+            );
+            match lock_reindex(pool).await? {
+                Some(lock) => {
+                    let pool = pool.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = reindex(&pool, lock).await {
+                            println!("Reindex failed {:?}", e);
+                        }
+                    });
+                }
+                None => println!("Reindex already running"),
+            }
+        }
+        Some(info) => println!("Search index {} is up to date", info.name),
+    }
+    Ok(())
+}
+
+/// Token of the `REINDEX_LOCK` held by a reindex
+pub struct ReindexLock {
+    token: String,
+}
+
+/// Takes `REINDEX_LOCK`, `None` when another reindex holds it
+pub async fn lock_reindex(pool: &Pool) -> Result<Option<ReindexLock>, crate::error::ApiError> {
+    let mut con = pool.get().await?;
+    let token = Ulid::new().to_string();
+    let locked: Option<String> = redis::cmd("SET")
+        .arg(REINDEX_LOCK)
+        .arg(&token)
+        .arg("NX")
+        .arg("PX")
+        .arg(REINDEX_LOCK_TTL.as_millis() as u64)
+        .query_async(&mut con)
+        .await?;
+    Ok(locked.map(|_| ReindexLock { token }))
+}
+
+/// Renews the lock, or releases it when `ttl_ms` is 0, if it's still ours
+async fn touch_lock(con: &mut Connection, lock: &ReindexLock, ttl_ms: u64) -> RedisResult<()> {
+    Script::new(
+        r#"if redis.call("GET", KEYS[1]) ~= ARGV[1] then return 0 end
+if ARGV[2] == "0" then return redis.call("DEL", KEYS[1]) end
+return redis.call("PEXPIRE", KEYS[1], ARGV[2])"#,
+    )
+    .key(REINDEX_LOCK)
+    .arg(&lock.token)
+    .arg(ttl_ms)
+    .invoke_async::<_, i64>(con)
+    .await?;
+    Ok(())
+}
+
+/// Builds `ArticleIdx_v{n+1}` next to the current index, waits until it has
+/// indexed the existing articles, then points the alias at it and drops the
+/// old index (documents are kept). Returns the new index name. Releases
+/// `lock` when done, whether it succeeded or not.
+pub async fn reindex(pool: &Pool, lock: ReindexLock) -> Result<String, crate::error::ApiError> {
+    let mut con = pool.get().await?;
+    let built = build_next_index(&mut con, &lock).await;
+    if let Err(e) = touch_lock(&mut con, &lock, 0).await {
+        println!("Reindex lock not released {:?}", e);
+    }
+    built
+}
+
+async fn build_next_index(
+    con: &mut Connection,
+    lock: &ReindexLock,
+) -> Result<String, crate::error::ApiError> {
+    let current = index_info(con).await?;
+    let version = current.as_ref().map(IndexInfo::version).unwrap_or(0) + 1;
+    let index = index_name(version);
+    println!("Building search index {}", index);
+    create_index_cmd(&index).query_async::<_, ()>(con).await?;
+    loop {
+        let info: Value = redis::cmd("FT.INFO").arg(&index).query_async(con).await?;
+        if !IndexInfo::from_value(&info)?.indexing {
+            break;
+        }
+        touch_lock(con, lock, REINDEX_LOCK_TTL.as_millis() as u64).await?;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    match current {
+        // the legacy index is named like the alias, it has to go first
+        Some(old) if old.name == INDEX_ALIAS => {
+            drop_index(con, &old.name).await?;
+            redis::cmd("FT.ALIASADD")
+                .arg(INDEX_ALIAS)
+                .arg(&index)
+                .query_async::<_, ()>(con)
+                .await?;
+        }
+        Some(old) => {
+            redis::cmd("FT.ALIASUPDATE")
+                .arg(INDEX_ALIAS)
+                .arg(&index)
+                .query_async::<_, ()>(con)
+                .await?;
+            drop_index(con, &old.name).await?;
+        }
+        None => {
+            redis::cmd("FT.ALIASADD")
+                .arg(INDEX_ALIAS)
+                .arg(&index)
+                .query_async::<_, ()>(con)
+                .await?;
+        }
+    }
+    println!("Search index {} is live", index);
+    Ok(index)
+}
+
+async fn drop_index(con: &mut Connection, index: &str) -> RedisResult<()> {
+    redis::cmd("FT.DROPINDEX")
+        .arg(index)
+        .query_async::<_, ()>(con)
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn data(s: &str) -> Value {
+        Value::Data(s.as_bytes().to_vec())
+    }
+
+    fn attribute(name: &str, kind: &str, options: &[&str]) -> Value {
+        let mut attribute = vec![
+            data("identifier"),
+            data(name),
+            data("attribute"),
+            data(name),
+            data("type"),
+            data(kind),
+        ];
+        attribute.extend(options.iter().map(|o| data(o)));
+        Value::Bulk(attribute)
+    }
+
+    #[test]
+    fn test_index_info_drift() {
+        let mut attributes: Vec<Value> = ARTICLE_SCHEMA
+            .iter()
+            .map(|f| attribute(f.name, f.kind, f.options))
+            .collect();
+        let reply = |attributes: Vec<Value>| {
+            Value::Bulk(vec![
+                data("index_name"),
+                data("ArticleIdx_v3"),
+                data("attributes"),
+                Value::Bulk(attributes),
+                data("indexing"),
+                Value::Int(0),
+            ])
+        };
+        let info = IndexInfo::from_value(&reply(attributes.clone())).unwrap();
+        assert_eq!(info.version(), 3);
+        assert!(!info.indexing);
+        assert!(!info.has_drift());
+
+        // FT.INFO lists the default weight and separator
+        attributes[1] = attribute("body", "TEXT", &["WEIGHT", "1"]);
+        let info = IndexInfo::from_value(&reply(attributes.clone())).unwrap();
+        assert!(!info.has_drift());
+
+        // the title weight changed
+        let mut reweighted = attributes.clone();
+        reweighted[0] = attribute("title", "TEXT", &["WEIGHT", "1"]);
+        let info = IndexInfo::from_value(&reply(reweighted)).unwrap();
+        assert!(info.has_drift());
+
+        // the legacy index had no description nor tags
+        attributes.truncate(3);
+        let info = IndexInfo::from_value(&reply(attributes)).unwrap();
+        assert!(info.has_drift());
+    }
+
+    #[test]
+    fn test_create_index_cmd() {
+        let packed =
+            String::from_utf8(create_index_cmd("ArticleIdx_v1").get_packed_command()).unwrap();
+        assert!(packed.contains("$4\r\ntags\r\n$3\r\nTAG\r\n$9\r\nSEPARATOR\r\n$1\r\n,\r\n"));
+    }
+}
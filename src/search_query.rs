@@ -0,0 +1,228 @@
+use poem_openapi::Object;
+
+use crate::error::ApiError;
+use crate::search_index::schema_field;
+
+/// Terms which have to occur in one field of the article
+#[derive(Debug, Clone, Object)]
+pub struct FieldTerm {
+    /// Name of a TEXT field, e.g. `title`
+    pub field: String,
+    pub text: String,
+}
+
+/// Inclusive range of unix timestamps, open ended when a bound is missing
+#[derive(Debug, Clone, Object)]
+pub struct DateRange {
+    /// NUMERIC field to filter on, defaults to `created`
+    pub field: Option<String>,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+}
+
+#[derive(Debug, Clone, Object)]
+pub struct SortBy {
+    /// A field marked `SORTABLE` in the index schema, e.g. `created`
+    pub field: String,
+    pub descending: Option<bool>,
+}
+
+/// Restrictions on top of the free text search term
+#[derive(Debug, Clone, Default, Object)]
+pub struct SearchFilter {
+    pub fields: Option<Vec<FieldTerm>>,
+    /// The article has to carry every one of these tags
+    pub tags: Option<Vec<String>>,
+    pub date_range: Option<DateRange>,
+    pub sort_by: Option<SortBy>,
+}
+
+/// A search compiled to RediSearch query syntax
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompiledQuery {
+    pub query: String,
+    /// SORTBY field and whether it is descending
+    pub sort_by: Option<(String, bool)>,
+}
+
+impl CompiledQuery {
+    /// Adds the query and its options to an `FT.SEARCH {index}` command
+    pub fn apply(&self, cmd: &mut redis::Cmd) {
+        cmd.arg(&self.query);
+        if let Some((field, descending)) = &self.sort_by {
+            cmd.arg("SORTBY")
+                .arg(field)
+                .arg(if *descending { "DESC" } else { "ASC" });
+        }
+    }
+}
+
+/// Escapes every character RediSearch treats as syntax or as a separator
+pub fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if (c.is_ascii_punctuation() && c != '_') || c.is_whitespace() {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Escapes each whitespace separated word, the words stay separate terms
+fn escape_text(text: &str) -> String {
+    text.split_whitespace()
+        .map(escape)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn checked_field(name: &str, kind: &str) -> Result<(), ApiError> {
+    match schema_field(name) {
+        Some(field) if field.kind == kind => Ok(()),
+        Some(field) => Err(ApiError::BadQuery(format!(
+            "field {} is {}, expected {}",
+            name, field.kind, kind
+        ))),
+        None => Err(ApiError::BadQuery(format!("unknown field {}", name))),
+    }
+}
+
+fn bound(value: Option<i64>, unbounded: &str) -> String {
+    value
+        .map(|v| v.to_string())
+        .unwrap_or_else(|| unbounded.to_string())
+}
+
+/// Compiles the search term and filter to a RediSearch query. The search
+/// term is escaped unless `raw` is set, in which case it is passed through
+/// as RediSearch syntax.
+pub fn compile(
+    search_term: &str,
+    raw: bool,
+    filter: Option<&SearchFilter>,
+) -> Result<CompiledQuery, ApiError> {
+    let mut parts = Vec::new();
+    let text = if raw {
+        search_term.trim().to_string()
+    } else {
+        escape_text(search_term)
+    };
+    if !text.is_empty() {
+        parts.push(text);
+    }
+    let mut sort_by = None;
+    if let Some(filter) = filter {
+        for term in filter.fields.iter().flatten() {
+            checked_field(&term.field, "TEXT")?;
+            let text = escape_text(&term.text);
+            if !text.is_empty() {
+                parts.push(format!("@{}:({})", term.field, text));
+            }
+        }
+        for tag in filter.tags.iter().flatten() {
+            let tag = tag.trim();
+            if !tag.is_empty() {
+                parts.push(format!("@tags:{{{}}}", escape(tag)));
+            }
+        }
+        if let Some(range) = &filter.date_range {
+            let field = range.field.as_deref().unwrap_or("created");
+            checked_field(field, "NUMERIC")?;
+            parts.push(format!(
+                "@{}:[{} {}]",
+                field,
+                bound(range.from, "-inf"),
+                bound(range.to, "+inf")
+            ));
+        }
+        if let Some(sort) = &filter.sort_by {
+            match schema_field(&sort.field) {
+                None => return Err(ApiError::BadQuery(format!("unknown field {}", sort.field))),
+                Some(f) if !f.options.contains(&"SORTABLE") => {
+                    return Err(ApiError::BadQuery(format!(
+                        "field {} is not sortable",
+                        sort.field
+                    )))
+                }
+                Some(_) => {}
+            }
+            sort_by = Some((sort.field.clone(), sort.descending.unwrap_or(false)));
+        }
+    }
+    let query = if parts.is_empty() {
+        "*".to_string()
+    } else {
+        parts.join(" ")
+    };
+    Ok(CompiledQuery { query, sort_by })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compile_escapes_and_filters() {
+        let filter = SearchFilter {
+            fields: Some(vec![FieldTerm {
+                field: "title".to_string(),
+                text: "machine learning".to_string(),
+            }]),
+            tags: Some(vec!["ml".to_string(), "c++ tools".to_string()]),
+            date_range: Some(DateRange {
+                field: None,
+                from: Some(1672531200),
+                to: None,
+            }),
+            sort_by: Some(SortBy {
+                field: "created".to_string(),
+                descending: Some(true),
+            }),
+        };
+        let compiled = compile("foo -bar @body:x", false, Some(&filter)).unwrap();
+        assert_eq!(
+            compiled.query,
+            "foo \\-bar \\@body\\:x @title:(machine learning) @tags:{ml} @tags:{c\\+\\+\\ tools} @created:[1672531200 +inf]"
+        );
+        assert_eq!(compiled.sort_by, Some(("created".to_string(), true)));
+
+        let compiled = compile("@title:foo*", true, None).unwrap();
+        assert_eq!(compiled.query, "@title:foo*");
+        assert_eq!(compile("  ", false, None).unwrap().query, "*");
+    }
+
+    #[test]
+    fn test_compile_rejects_unknown_fields() {
+        let filter = SearchFilter {
+            fields: Some(vec![FieldTerm {
+                field: "tags".to_string(),
+                text: "ml".to_string(),
+            }]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            compile("foo", false, Some(&filter)),
+            Err(ApiError::BadQuery(_))
+        ));
+        let filter = SearchFilter {
+            sort_by: Some(SortBy {
+                field: "nope".to_string(),
+                descending: None,
+            }),
+            ..Default::default()
+        };
+        assert!(compile("foo", false, Some(&filter)).is_err());
+        let filter = SearchFilter {
+            sort_by: Some(SortBy {
+                field: "title".to_string(),
+                descending: None,
+            }),
+            ..Default::default()
+        };
+        assert!(matches!(
+            compile("foo", false, Some(&filter)),
+            Err(ApiError::BadQuery(_))
+        ));
+    }
+}
@@ -1,64 +1,153 @@
 use std::env;
 
-use std::path::PathBuf;
-use config::{ConfigError, Config, File, Environment};
+use config::{Config, ConfigError, Environment, File};
+use deadpool_redis::{PoolConfig, Runtime, Timeouts};
 use directories::ProjectDirs;
 use serde_derive::Deserialize;
+use std::path::PathBuf;
+use std::time::Duration;
+use terraphim_pipeline::shard::Sharding;
+
+use crate::redis_pool::{ClusterPool, CreatePoolError, Pool};
+
+const DEFAULT_POOL_SIZE: usize = 16;
+const DEFAULT_WAIT_TIMEOUT_MS: u64 = 5000;
 
 /// Configuration for the server.
 /// These values are set when the server initializes, and do not change while running.
-/// These are constructed from default or local files and ENV variables. 
+/// These are constructed from default or local files and ENV variables.
 #[derive(Clone, Debug, Deserialize)]
 pub struct Settings {
     /// The address to listen on
     pub server_url: String,
     pub redis_url: String,
+    /// `standalone` to use `redis_url`, `cluster` to use `redis_cluster_url`
+    pub redis_mode: String,
+    /// Comma separated urls of Redis Cluster nodes, used in `cluster` mode
     pub redis_cluster_url: String,
     pub config_dir: PathBuf,
     pub api_endpoint: String,
+    /// Path to the desktop config declaring the roles
+    pub role_config: PathBuf,
+    /// Role used when a request does not name one
+    pub default_role: String,
+    /// Merge edges into the graph while handling the request. Disable when
+    /// the `edges_worker` consumes the `edges_matched_*` streams instead.
+    pub inline_graph_writes: bool,
+    /// Maximum number of pooled Redis connections, 16 by default. Not
+    /// accepted in `cluster` mode, a cluster shares one connection.
+    pub redis_pool_size: Option<usize>,
+    /// How long to wait for a new Redis connection, in milliseconds
+    pub redis_connect_timeout_ms: u64,
+    /// How long a handler waits for a free pooled connection, in
+    /// milliseconds, 5000 by default. Not accepted in `cluster` mode.
+    pub redis_wait_timeout_ms: Option<u64>,
+    /// Link of concepts in annotated HTML, `{id}` is replaced by the concept id
+    pub concept_link: String,
+    /// What picks the `edges_matched_*` shard of an edge: `source`, `article` or `role`
+    pub edge_shard_strategy: String,
+    /// Number of `edges_matched_*` streams per role, give the `edges_worker` the same count
+    pub edge_shard_count: u32,
 }
 impl Settings {
     pub fn new() -> Result<Self, ConfigError> {
+        let mut settings = Config::builder();
 
-    let mut settings = Config::builder();
+        // Start off by merging in the "default" configuration file
+        // settings.merge(File::with_name("config/default"))?;
+        let env = env::var("RUN_MODE").unwrap_or_else(|_| "development".into());
+        println!("env: {}", env);
+        settings = settings.set_default("redis_mode", "standalone")?;
+        settings = settings.set_default("redis_cluster_url", "")?;
+        settings = settings.set_default("role_config", "config/desktop_config.json")?;
+        settings = settings.set_default("default_role", "project-manager")?;
+        settings = settings.set_default("inline_graph_writes", true)?;
+        settings = settings.set_default("redis_connect_timeout_ms", 1000)?;
+        settings = settings.set_default("concept_link", "/concepts/{id}")?;
+        settings = settings.set_default("edge_shard_strategy", "source")?;
+        settings = settings.set_default("edge_shard_count", 1)?;
+        if let Some(proj_dirs) = ProjectDirs::from("com", "aks", "terraphim") {
+            let config_dir = proj_dirs.config_dir();
+            println!("Project Dir {:?}", config_dir);
+            settings = settings.set_default("config_dir", config_dir.to_str())?;
+            println!("Create folder if doesn't exist");
+            std::fs::create_dir_all(proj_dirs.config_dir()).unwrap();
+            let filename = proj_dirs.config_dir().join("config.toml");
+
+            if filename.exists() {
+                println!("File exists");
+                println!("{:?}", filename);
+            } else {
+                println!("File does not exist");
+                std::fs::copy("config/default.toml", &filename).unwrap();
+            }
+
+            settings = settings.add_source(File::with_name(filename.to_str().unwrap()));
+        }
 
-    // Start off by merging in the "default" configuration file
-    // settings.merge(File::with_name("config/default"))?;
-    let env = env::var("RUN_MODE").unwrap_or_else(|_| "development".into());
-    println!("env: {}", env);
-    if let Some(proj_dirs) = ProjectDirs::from("com", "aks",  "terraphim") {
-        let config_dir=proj_dirs.config_dir();
-        println!("Project Dir {:?}", config_dir);
-        settings = settings.set_default("config_dir", config_dir.to_str())?;
-        println!("Create folder if doesn't exist");
-        std::fs::create_dir_all(proj_dirs.config_dir()).unwrap();
-        let filename= proj_dirs.config_dir().join("config.toml");
-        
-        if filename.exists() {
-            println!("File exists");
-            println!("{:?}", filename);
-        } else {
-            println!("File does not exist");
-            std::fs::copy("config/default.toml", &filename).unwrap();
+        // settings.merge(File::with_name(".env"))?;
+        settings = settings.add_source(Environment::with_prefix("TERRAPHIM"));
+        match settings.build() {
+            Ok(config) => {
+                println!("Settings: {:?}", config);
+                Ok(config.try_deserialize())?
+            }
+            Err(e) => {
+                println!("Error: {:?}", e);
+                Err(e)
+            }
         }
-        
-        settings=settings.add_source(File::with_name(filename.to_str().unwrap()));  
-        
+    }
+}
 
+impl Settings {
+    /// Shard assignment of the edge streams
+    pub fn sharding(&self) -> Result<Sharding, String> {
+        Sharding::new(&self.edge_shard_strategy, self.edge_shard_count)
     }
 
-    // settings.merge(File::with_name(".env"))?;
-    settings=settings.add_source(Environment::with_prefix("TERRAPHIM"));
-    match settings.build() {
-        Ok(config) => {
-            println!("Settings: {:?}", config);
-            Ok(config.try_deserialize())?
-        },
-        Err(e) => {
-            println!("Error: {:?}", e);
-            Err(e)
+    /// Async Redis connection pool shared by all handlers, of the cluster
+    /// in `cluster` mode. A cluster only honours `redis_connect_timeout_ms`,
+    /// the pool size and wait timeout are rejected rather than silently ignored.
+    pub fn redis_pool(&self) -> Result<Pool, CreatePoolError> {
+        let connect_timeout = Duration::from_millis(self.redis_connect_timeout_ms);
+        match self.redis_mode.as_str() {
+            "standalone" => {}
+            "cluster" => return self.cluster_pool(connect_timeout),
+            mode => return Err(CreatePoolError::Mode(mode.to_string())),
         }
+        let mut config = deadpool_redis::Config::from_url(self.redis_url.clone());
+        config.pool = Some(PoolConfig {
+            max_size: self.redis_pool_size.unwrap_or(DEFAULT_POOL_SIZE),
+            timeouts: Timeouts {
+                wait: Some(Duration::from_millis(
+                    self.redis_wait_timeout_ms
+                        .unwrap_or(DEFAULT_WAIT_TIMEOUT_MS),
+                )),
+                create: Some(connect_timeout),
+                recycle: Some(connect_timeout),
+            },
+        });
+        Ok(Pool::Standalone(config.create_pool(Some(Runtime::Tokio1))?))
     }
 
+    fn cluster_pool(&self, connect_timeout: Duration) -> Result<Pool, CreatePoolError> {
+        let nodes: Vec<String> = self
+            .redis_cluster_url
+            .split(',')
+            .map(str::trim)
+            .filter(|url| !url.is_empty())
+            .map(str::to_string)
+            .collect();
+        if nodes.is_empty() {
+            return Err(CreatePoolError::NoClusterNodes);
+        }
+        if self.redis_pool_size.is_some() {
+            return Err(CreatePoolError::ClusterSetting("redis_pool_size"));
+        }
+        if self.redis_wait_timeout_ms.is_some() {
+            return Err(CreatePoolError::ClusterSetting("redis_wait_timeout_ms"));
+        }
+        Ok(Pool::Cluster(ClusterPool::new(nodes, connect_timeout)?))
     }
-}
\ No newline at end of file
+}